@@ -0,0 +1,164 @@
+use std::time::Instant;
+
+use itertools::{EitherOrBoth, Itertools};
+use crate::{CQF, CqfError, FilterItem, ResizeEvent};
+
+// a batch at least this fraction of the filter's size is merged with it in one pass rather than
+// inserted item by item
+const REBUILD_FRACTION: u64 = 4;
+// how many keys ahead of the one being looked up have their blocks prefetched
+const PREFETCH_DISTANCE: usize = 16;
+// a batch of at least this fraction of the table's slots is looked up in quotient order, since
+// its keys share blocks often enough that going through the table front to back beats prefetching
+const SORTED_QUERY_FRACTION: u64 = 8;
+
+impl CQF {
+    pub fn insert_batch(&mut self, items: &[u64]) -> Result<(), CqfError> {
+        let hashes = items.iter().map(|&item| (self.calc_hash(item), 1)).collect_vec();
+        self.insert_hashes(hashes)
+    }
+
+    pub fn insert_counts(&mut self, items: &[(u64, u64)]) -> Result<(), CqfError> {
+        let hashes = items.iter().filter(|&&(_, count)| count > 0).map(|&(item, count)| (self.calc_hash(item), count)).collect_vec();
+        self.insert_hashes(hashes)
+    }
+
+    // counts[i] is set to the count of items[i]. counts is left alone if it isn't the same
+    // length as items
+    pub fn query_batch(&self, items: &[u64], counts: &mut [u64]) -> Result<(), CqfError> {
+        if items.len() != counts.len() {
+            return Err(CqfError::InvalidParameters("query_batch needs a count for every item"));
+        }
+        let hashes = items.iter().map(|&item| self.calc_hash(item)).collect_vec();
+        self.query_hashes(&hashes, counts);
+        Ok(())
+    }
+
+    pub fn query_batch_by_hash(&self, hashes: &[u64], counts: &mut [u64]) -> Result<(), CqfError> {
+        if hashes.len() != counts.len() {
+            return Err(CqfError::InvalidParameters("query_batch needs a count for every hash"));
+        }
+        self.query_hashes(hashes, counts);
+        Ok(())
+    }
+
+    fn query_hashes(&self, hashes: &[u64], counts: &mut [u64]) {
+        if hashes.len() as u64 * SORTED_QUERY_FRACTION >= self.nslots {
+            // sorted with where each hash came from, so the counts can go back in input order
+            let mut sorted = hashes.iter().enumerate().map(|(i, &hash)| (hash, i as u64)).collect_vec();
+            radix_sort(&mut sorted, self.hash_bits());
+            for (hash, i) in sorted {
+                counts[i as usize] = self.query_by_hash(hash);
+            }
+            return;
+        }
+        // every lookup's block and first slots are on their way in by the time it gets to them
+        for &hash in hashes.iter().take(PREFETCH_DISTANCE) {
+            self.prefetch(hash);
+        }
+        for (i, &hash) in hashes.iter().enumerate() {
+            if let Some(&ahead) = hashes.get(i + PREFETCH_DISTANCE) {
+                self.prefetch(ahead);
+            }
+            counts[i] = self.query_by_hash(hash);
+        }
+    }
+
+    fn prefetch(&self, hash: u64) {
+        let (quotient, _) = self.calc_qr(hash);
+        prefetch(&self.blocks[quotient / 64]);
+        prefetch(&self.slots[quotient * self.slot_bits() as usize / 64]);
+    }
+
+    // sorts and adds up the batch first, so every key is only looked up once and the table is
+    // gone through front to back
+    fn insert_hashes(&mut self, mut hashes: Vec<(u64, u64)>) -> Result<(), CqfError> {
+        radix_sort(&mut hashes, self.hash_bits());
+        let mut batch: Vec<(u64, u64)> = Vec::with_capacity(hashes.len());
+        for (hash, count) in hashes {
+            match batch.last_mut() {
+                Some((last, total)) if *last == hash => *total = total.checked_add(count).ok_or(CqfError::CountOverflow)?,
+                _ => batch.push((hash, count)),
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // one resize up front for the whole batch. Counts past 2 are written as the remainder
+        // twice around at least one digit, so they're counted as 3 slots
+        let batch_slots: u64 = batch.iter().map(|&(_, count)| count.min(3)).sum();
+        let mut bits = self.growth_to_fit(self.noccupied_slots + batch_slots);
+        if (batch.len() as u64) * REBUILD_FRACTION < self.noccupied_slots {
+            if bits > 0 {
+                self.grow_by(bits)?;
+            }
+            for (hash, count) in batch {
+                self.insert_with_value(hash, 0, count)?;
+            }
+            return Ok(());
+        }
+
+        let old_nslots = self.nslots;
+        let start = Instant::now();
+        let step = self.resize_policy.growth_bits().unwrap_or_default();
+        let mut started = false;
+        loop {
+            if bits > 0 && !started {
+                self.emit(ResizeEvent::Started { old_nslots, new_nslots: old_nslots << bits, load_factor: self.get_load_factor() });
+                started = true;
+            }
+            let mut new = self.empty_like(self.lognslots + bits, self.quotient_bits + bits)?;
+            let merged = self.into_iter().merge_join_by(&batch, |item, &&(hash, _)| item.hash.cmp(&hash)).map(|either| match either {
+                EitherOrBoth::Left(item) => Ok(item),
+                EitherOrBoth::Right(&(hash, count)) => Ok(FilterItem { hash, item: None, count, value: 0 }),
+                EitherOrBoth::Both(item, &(_, count)) => item.count.checked_add(count).map(|count| FilterItem { count, ..item }).ok_or(CqfError::CountOverflow),
+            });
+            match new.fill_sorted(merged) {
+                // counters with more than one digit take more room than they were counted as
+                Err(CqfError::CapacityExceeded) if step > 0 && self.can_grow_by(bits + step) => bits += step,
+                result => {
+                    result?;
+                    *self = new;
+                    if started {
+                        self.emit(ResizeEvent::Finished { old_nslots, new_nslots: self.nslots, elapsed: start.elapsed() });
+                    }
+                    return Ok(());
+                },
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn prefetch<T>(item: &T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::x86_64::_mm_prefetch(item as *const T as *const i8, std::arch::x86_64::_MM_HINT_T0);
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = item;
+}
+
+// least significant byte first, skipping the bytes past the hash width and any every hash shares
+fn radix_sort(items: &mut Vec<(u64, u64)>, hash_bits: u64) {
+    let mut scratch = vec![(0, 0); items.len()];
+    for shift in (0..hash_bits).step_by(8) {
+        let mut counts = [0usize; 256];
+        for &(hash, _) in items.iter() {
+            counts[((hash >> shift) & 0xff) as usize] += 1;
+        }
+        if counts.contains(&items.len()) {
+            continue;
+        }
+        let mut offset = 0;
+        for count in counts.iter_mut() {
+            (*count, offset) = (offset, offset + *count);
+        }
+        for &item in items.iter() {
+            let digit = ((item.0 >> shift) & 0xff) as usize;
+            scratch[counts[digit]] = item;
+            counts[digit] += 1;
+        }
+        std::mem::swap(items, &mut scratch);
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{Read, Write};
+
+use crate::{Block, CQF, CqfError, HashMode, Storage};
+
+// the reference C CQF writes its packed qfmetadata struct followed by its packed blocks: a u8
+// offset, the occupieds and runends words, then 64 slots of bits_per_slot bits each. It's
+// written in host order, which is little-endian everywhere it runs in practice
+const MAGIC: u64 = 0x0102030405060708;
+const METADATA_LEN: usize = 124;
+const BLOCK_HEADER_LEN: usize = 17;
+
+// the C CQF's qf_hashmode enum
+const QF_HASH_DEFAULT: u32 = 0;
+const QF_HASH_INVERTIBLE: u32 = 1;
+const QF_HASH_NONE: u32 = 2;
+
+type Result<T> = std::result::Result<T, CqfError>;
+
+fn block_len(bits_per_slot: u64) -> usize {
+    BLOCK_HEADER_LEN + 8 * bits_per_slot as usize
+}
+
+pub(crate) fn write<W: Write>(qf: &CQF, writer: &mut W) -> Result<()> {
+    // the C CQF works out the quotient width from nslots, and has no value for our Fast hash
+    if qf.quotient_bits != qf.lognslots {
+        return Err(CqfError::IncompatibleFormat("quotient widths other than lognslots"));
+    }
+    let hash_mode = match qf.hash_mode {
+        HashMode::Murmur => QF_HASH_DEFAULT,
+        HashMode::Invertible => QF_HASH_INVERTIBLE,
+        HashMode::None => QF_HASH_NONE,
+        HashMode::Fast => return Err(CqfError::IncompatibleFormat("the Fast hash mode")),
+    };
+    let Ok(seed) = u32::try_from(qf.seed) else {
+        return Err(CqfError::IncompatibleFormat("seeds wider than 32 bits"));
+    };
+    let (mut nelts, mut ndistinct_elts) = (0u64, 0u64);
+    for item in qf.into_iter() {
+        nelts = nelts.saturating_add(item.count);
+        ndistinct_elts += 1;
+    }
+    let key_bits = qf.quotient_bits + qf.remainder_bits;
+
+    let mut metadata = Vec::with_capacity(METADATA_LEN);
+    metadata.extend_from_slice(&MAGIC.to_le_bytes());
+    metadata.extend_from_slice(&hash_mode.to_le_bytes());
+    metadata.extend_from_slice(&0u32.to_le_bytes());
+    metadata.extend_from_slice(&(qf.nblocks * block_len(qf.slot_bits()) as u64).to_le_bytes());
+    metadata.extend_from_slice(&seed.to_le_bytes());
+    for field in [qf.nslots, qf.xnslots, key_bits, qf.value_bits, qf.remainder_bits, qf.slot_bits()] {
+        metadata.extend_from_slice(&field.to_le_bytes());
+    }
+    metadata.extend_from_slice(&(1u128 << key_bits).to_le_bytes());
+    for field in [qf.nblocks, nelts, ndistinct_elts, qf.noccupied_slots] {
+        metadata.extend_from_slice(&field.to_le_bytes());
+    }
+    writer.write_all(&metadata)?;
+
+    let words_per_block = qf.slot_bits() as usize;
+    let mut block = Vec::with_capacity(block_len(qf.slot_bits()));
+    for (i, qf_block) in qf.blocks.iter().enumerate() {
+        block.clear();
+        // offsets are a u8 there, saturated the same way the C CQF does it
+        block.push(qf_block.offset.min(u8::MAX as u64) as u8);
+        block.extend_from_slice(&qf_block.occupieds.to_le_bytes());
+        block.extend_from_slice(&qf_block.runends.to_le_bytes());
+        for word in &qf.slots[i * words_per_block..(i + 1) * words_per_block] {
+            block.extend_from_slice(&word.to_le_bytes());
+        }
+        writer.write_all(&block)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub(crate) fn read<R: Read>(reader: &mut R) -> Result<CQF> {
+    let mut metadata = [0; METADATA_LEN];
+    reader.read_exact(&mut metadata)?;
+    let u32_at = |at: usize| u32::from_le_bytes(metadata[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_le_bytes(metadata[at..at + 8].try_into().unwrap());
+    if u64_at(0) != MAGIC {
+        return Err(if u64_at(0) == MAGIC.swap_bytes() { CqfError::UnsupportedEndianness } else { CqfError::BadMagic });
+    }
+    let hash_mode = match u32_at(8) {
+        QF_HASH_DEFAULT => HashMode::Murmur,
+        QF_HASH_INVERTIBLE => HashMode::Invertible,
+        QF_HASH_NONE => HashMode::None,
+        _ => return Err(CqfError::InvalidHeader("hash mode")),
+    };
+    let total_size_in_bytes = u64_at(16);
+    let seed = u32_at(24);
+    let nslots = u64_at(28);
+    let xnslots = u64_at(36);
+    let key_bits = u64_at(44);
+    let value_bits = u64_at(52);
+    let key_remainder_bits = u64_at(60);
+    let bits_per_slot = u64_at(68);
+    let nblocks = u64_at(92);
+    let noccupied_slots = u64_at(116);
+
+    if !nslots.is_power_of_two() {
+        return Err(CqfError::InvalidHeader("nslots"));
+    }
+    let lognslots = nslots.ilog2() as u64;
+    if xnslots < nslots || nblocks != xnslots.div_ceil(64) {
+        return Err(CqfError::InvalidHeader("xnslots"));
+    }
+    // values sit below the remainder in each slot the same way ours do. The C CQF can keep several
+    // values for one key, which queries here see as the first of them
+    if bits_per_slot != key_remainder_bits.saturating_add(value_bits) || bits_per_slot > 64 {
+        return Err(CqfError::InvalidHeader("bits per slot"));
+    }
+    if key_bits != lognslots + key_remainder_bits || key_bits > 64 {
+        return Err(CqfError::InvalidHeader("key bits"));
+    }
+    if key_remainder_bits < 2 {
+        return Err(CqfError::IncompatibleFormat("remainders narrower than 2 bits"));
+    }
+    if key_remainder_bits >= 64 {
+        return Err(CqfError::IncompatibleFormat("64-bit remainders"));
+    }
+    if nblocks.checked_mul(block_len(bits_per_slot) as u64) != Some(total_size_in_bytes) {
+        return Err(CqfError::InvalidHeader("total size"));
+    }
+    if noccupied_slots > xnslots {
+        return Err(CqfError::InvalidHeader("occupied slot count"));
+    }
+
+    let words_per_block = bits_per_slot as usize;
+    // the header's sizes aren't trusted to reserve room up front, the vecs grow as blocks turn up
+    let mut blocks = Vec::new();
+    let mut slots = Vec::new();
+    let mut block = vec![0; block_len(bits_per_slot)];
+    for _ in 0..nblocks {
+        reader.read_exact(&mut block)?;
+        let word_at = |at: usize| u64::from_le_bytes(block[at..at + 8].try_into().unwrap());
+        // the u8 offset may have saturated, so it's worked out again below
+        blocks.push(Block { offset: 0, occupieds: word_at(1), runends: word_at(9) });
+        slots.extend((0..words_per_block).map(|i| word_at(BLOCK_HEADER_LEN + 8 * i)));
+    }
+
+    let mut qf = CQF {
+        lognslots,
+        nslots,
+        xnslots,
+        nblocks,
+        noccupied_slots,
+        quotient_bits: lognslots,
+        remainder_bits: key_remainder_bits,
+        value_bits,
+        hash_mode,
+        seed: seed as u64,
+        blocks: Storage::owned(blocks),
+        slots: Storage::owned(slots),
+        ..Default::default()
+    };
+    qf.recompute_offsets();
+    Ok(qf)
+}
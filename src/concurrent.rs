@@ -0,0 +1,240 @@
+use std::hint::spin_loop;
+use std::thread::yield_now;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::{CQF, CqfError, CqfKey, HashMode};
+
+// same granularity as the C CQF's spinlocks
+const NUM_SLOTS_TO_LOCK: usize = 4096;
+
+// threads share the CQF itself, and only ever write its table a block or slot word at a time
+// (see CQF::update_block), under the region locks covering it. Nothing but a resize, holding the
+// write half of the resize lock, ever gets a &mut CQF
+struct Table {
+    qf: CQF,
+    locks: Vec<AtomicBool>,
+}
+
+impl Table {
+    fn new(qf: CQF) -> Self {
+        let nregions = (qf.xnslots as usize).div_ceil(NUM_SLOTS_TO_LOCK);
+        Self {
+            qf,
+            locks: (0..nregions).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    fn qf(&self) -> &CQF {
+        &self.qf
+    }
+
+    // locks every region the cluster holding `quotient` runs through, plus the region after it
+    // since fixing up offsets after a remove looks at the block just past the cluster
+    fn lock_cluster(&self, quotient: usize) -> RegionGuard<'_> {
+        let xnslots = self.qf().xnslots as usize;
+        let mut guard = RegionGuard::new(&self.locks, quotient.saturating_sub(1) / NUM_SLOTS_TO_LOCK);
+        let mut index = quotient;
+        loop {
+            // each region gets locked before any of its blocks are looked at
+            guard.extend_to(index / NUM_SLOTS_TO_LOCK);
+            if index >= xnslots {
+                break;
+            }
+            let t = self.qf().offset_lower_bound(index);
+            if t == 0 {
+                break;
+            }
+            index += t as usize;
+        }
+        guard.extend_to(index / NUM_SLOTS_TO_LOCK + 1);
+        guard
+    }
+}
+
+// a run of consecutive regions held by one operation, always taken in ascending order
+struct RegionGuard<'a> {
+    locks: &'a [AtomicBool],
+    first: usize,
+    end: usize,
+}
+
+impl<'a> RegionGuard<'a> {
+    fn new(locks: &'a [AtomicBool], first: usize) -> Self {
+        lock(&locks[first]);
+        Self { locks, first, end: first + 1 }
+    }
+
+    fn extend_to(&mut self, region: usize) {
+        while self.end <= region && self.end < self.locks.len() {
+            lock(&self.locks[self.end]);
+            self.end += 1;
+        }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.end == self.locks.len()
+    }
+
+    // first slot that isn't covered by the held regions
+    fn limit(&self, xnslots: u64) -> usize {
+        (self.end * NUM_SLOTS_TO_LOCK).min(xnslots as usize)
+    }
+}
+
+impl Drop for RegionGuard<'_> {
+    fn drop(&mut self) {
+        for lock in &self.locks[self.first..self.end] {
+            lock.store(false, Ordering::Release);
+        }
+    }
+}
+
+fn lock(lock: &AtomicBool) {
+    let mut spins = 0;
+    while lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        while lock.load(Ordering::Relaxed) {
+            // back off to the scheduler in case whoever holds it isn't running
+            if spins < 64 {
+                spins += 1;
+                spin_loop();
+            } else {
+                yield_now();
+            }
+        }
+    }
+}
+
+pub struct ConcurrentCQF {
+    // readers are inserts and queries, the writer is a resize swapping in the new table
+    table: RwLock<Table>,
+    noccupied_slots: AtomicU64,
+}
+
+impl ConcurrentCQF {
+    pub fn new(qf: CQF) -> Self {
+        Self {
+            noccupied_slots: AtomicU64::new(qf.noccupied_slots),
+            table: RwLock::new(Table::new(qf)),
+        }
+    }
+
+    pub fn build(lognslots: u64, key_bits: u64, mode: HashMode) -> Result<Self, CqfError> {
+        Ok(Self::new(CQF::try_build(lognslots, key_bits, mode)?))
+    }
+
+    pub fn into_inner(self) -> CQF {
+        let mut qf = self.table.into_inner().expect("CQF lock poisoned!").qf;
+        qf.noccupied_slots = self.noccupied_slots.into_inner();
+        qf
+    }
+
+    pub fn get_load_factor(&self) -> f32 {
+        let table = self.table.read().expect("CQF lock poisoned!");
+        self.noccupied_slots.load(Ordering::Relaxed) as f32 / table.qf().xnslots as f32
+    }
+
+    // resizing keeps the hash width, so hashes stay valid across a concurrent resize
+    fn calc_hash(&self, item: u64) -> u64 {
+        self.table.read().expect("CQF lock poisoned!").qf().calc_hash(item)
+    }
+
+    pub fn hash_key<K: CqfKey + ?Sized>(&self, key: &K) -> u64 {
+        self.table.read().expect("CQF lock poisoned!").qf().hash_key(key)
+    }
+
+    pub fn insert(&self, item: u64, count: u64) -> Result<(), CqfError> {
+        self.insert_by_hash(self.calc_hash(item), count)
+    }
+
+    pub fn insert_key<K: CqfKey + ?Sized>(&self, key: &K, count: u64) -> Result<(), CqfError> {
+        self.insert_by_hash(self.hash_key(key), count)
+    }
+
+    pub fn insert_by_hash(&self, hash: u64, count: u64) -> Result<(), CqfError> {
+        loop {
+            let table = self.table.read().expect("CQF lock poisoned!");
+            let xnslots = table.qf().xnslots;
+            let (quotient, _) = table.qf().calc_qr(hash);
+            let mut guard = table.lock_cluster(quotient);
+            loop {
+                let limit = guard.limit(xnslots);
+                // SAFETY: insert_within only looks below limit, and everything below it from
+                // the region before the quotient's is locked
+                match unsafe { table.qf().insert_within(hash, 0, count, limit) }? {
+                    Some(nslots) => {
+                        let noccupied = self.noccupied_slots.fetch_add(nslots, Ordering::Relaxed) + nslots;
+                        let should_grow = table.qf().should_grow(noccupied);
+                        drop(guard);
+                        drop(table);
+                        if should_grow {
+                            self.grow(xnslots)?;
+                        }
+                        return Ok(());
+                    },
+                    None if guard.is_at_end() => break,
+                    None => guard.extend_to(guard.end),
+                }
+            }
+            // out of room at the end of the table, so grow it and go again
+            drop(guard);
+            drop(table);
+            self.grow(xnslots)?;
+        }
+    }
+
+    // grows the table unless another thread already did it while we waited for the lock
+    fn grow(&self, xnslots: u64) -> Result<(), CqfError> {
+        let mut table = self.table.write().expect("CQF lock poisoned!");
+        let qf = &mut table.qf;
+        if qf.xnslots != xnslots {
+            return Ok(());
+        }
+        qf.noccupied_slots = self.noccupied_slots.load(Ordering::Relaxed);
+        qf.grow()?;
+        let qf = std::mem::take(qf);
+        self.noccupied_slots.store(qf.noccupied_slots, Ordering::Relaxed);
+        *table = Table::new(qf);
+        Ok(())
+    }
+
+    pub fn remove(&self, item: u64, count: u64) -> Result<u64, CqfError> {
+        self.remove_by_hash(self.calc_hash(item), count)
+    }
+
+    pub fn delete_all(&self, item: u64) -> Result<u64, CqfError> {
+        self.remove(item, u64::MAX)
+    }
+
+    pub fn remove_by_hash(&self, hash: u64, count: u64) -> Result<u64, CqfError> {
+        let table = self.table.read().expect("CQF lock poisoned!");
+        let (quotient, _) = table.qf().calc_qr(hash);
+        let _guard = table.lock_cluster(quotient);
+        // SAFETY: removing only shifts slots back within the locked cluster, and fixes up offsets
+        // no further than the region after it
+        let (removed, nfreed) = unsafe { table.qf().remove_in_place(hash, count) };
+        self.noccupied_slots.fetch_sub(nfreed, Ordering::Relaxed);
+        Ok(removed)
+    }
+
+    pub fn query(&self, item: u64) -> u64 {
+        self.query_by_hash(self.calc_hash(item))
+    }
+
+    pub fn query_key<K: CqfKey + ?Sized>(&self, key: &K) -> u64 {
+        self.query_by_hash(self.hash_key(key))
+    }
+
+    pub fn query_by_hash(&self, hash: u64) -> u64 {
+        let table = self.table.read().expect("CQF lock poisoned!");
+        let (quotient, _) = table.qf().calc_qr(hash);
+        let _guard = table.lock_cluster(quotient);
+        table.qf().query_by_hash(hash)
+    }
+}
+
+impl From<CQF> for ConcurrentCQF {
+    fn from(qf: CQF) -> Self {
+        Self::new(qf)
+    }
+}
@@ -1,214 +1,538 @@
-use std::{usize, path::PathBuf, fs::File};
-use std::io::{BufWriter, BufReader};
+use std::{path::PathBuf, fs::File};
+use std::io::{BufWriter, BufReader, Read, Write};
 use bitintr::{Pdep, Tzcnt, Popcnt};
-use xxhash_rust::xxh3::xxh3_64;
-use itertools::Itertools;
-use bincode::{Encode, Decode};
-use anyhow::Result;
-
-#[derive(Encode, Decode, Clone, Copy)]
-struct Block {
-    offset: u16,
-    occupieds: u64,
-    runends: u64,
-    counts: u64,
-    remainders: [u64; 64]
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+use itertools::{EitherOrBoth, Itertools};
+use std::time::Instant;
+use crate::{CQFOptions, CqfError, CqfKey, key, ResizeEvent, ResizeHook, ResizePolicy, Storage, cformat, format};
+
+// laid out exactly as it sits on disk, so mapped files can be read in place
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Block {
+    pub(crate) offset: u64,
+    pub(crate) occupieds: u64,
+    pub(crate) runends: u64
 }
 
-#[derive(Encode, Decode, PartialEq, Clone, Copy, Debug, Default)]
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
 pub enum HashMode {
+    // the key is its own hash. Keys wider than the hash width are cut down to their low bits, so
+    // two that only differ above it are counted as the same key
     None,
     Invertible,
     #[default]
-    Fast
+    Fast,
+    // MurmurHash64A, the C CQF's default hash, so filters imported from it can still be queried
+    Murmur
 }
 
-#[derive(Encode, Decode, Default)]
+// what happens when a key that already has a value is given a different one, by an insert or by
+// merging filters that both have it
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum ValueMerge {
+    #[default]
+    Keep,
+    Replace,
+    Min,
+    Max,
+    // for values that are bitsets, like a set of colors
+    Or,
+    // fail with ValueCollision and leave the filter as it was
+    Reject
+}
+
+impl ValueMerge {
+    fn apply(&self, old: u64, new: u64) -> Result<u64, CqfError> {
+        Ok(match self {
+            ValueMerge::Keep => old,
+            ValueMerge::Replace => new,
+            ValueMerge::Min => old.min(new),
+            ValueMerge::Max => old.max(new),
+            ValueMerge::Or => old | new,
+            ValueMerge::Reject if old != new => return Err(CqfError::ValueCollision),
+            ValueMerge::Reject => old,
+        })
+    }
+}
+
+// one block's worth of slots
+const MIN_SORTED_LOGNSLOTS: u64 = 6;
+
+#[derive(Default)]
 pub struct CQF {
-    lognslots: u64,
-    nslots: u64,
-    xnslots: u64,
-    nblocks: u64,
-    noccupied_slots: u64,
-    quotient_bits: u64,
-    remainder_bits: u64,
-    hash_mode: HashMode,
-    blocks: Vec<Block>
+    pub(crate) lognslots: u64,
+    pub(crate) nslots: u64,
+    pub(crate) xnslots: u64,
+    pub(crate) nblocks: u64,
+    pub(crate) noccupied_slots: u64,
+    pub(crate) quotient_bits: u64,
+    pub(crate) remainder_bits: u64,
+    // slots hold the remainder shifted up past a value_bits-wide value, like the C CQF's
+    pub(crate) value_bits: u64,
+    pub(crate) hash_mode: HashMode,
+    // seeds the xxh3 hashes of Fast mode and of keys
+    pub(crate) seed: u64,
+    pub(crate) blocks: Storage<Block>,
+    // slot_bits-wide slots packed back to back, so each block owns slot_bits words
+    pub(crate) slots: Storage<u64>,
+    // none of these are saved with the filter, a loaded one gets the defaults and no hook
+    pub(crate) resize_policy: ResizePolicy,
+    pub(crate) resize_hook: Option<ResizeHook>,
+    pub(crate) value_merge: ValueMerge
 }
 
 impl CQF {
-    pub fn build(lognslots: u64, key_bits: u64, hash_mode: HashMode) -> Self {
-        let nslots = 1 << lognslots;
-        let xnslots: u64 = (nslots as f32 + 10.0*((nslots as f32).sqrt())) as u64;
-        let nblocks = (xnslots + 63) / 64;
-        let mut blockvec: Vec<Block> = Vec::with_capacity(nblocks.try_into().unwrap());
-        for _ in 0..nblocks {
-            blockvec.push(Block {
-                offset: 0,
-                occupieds: 0,
-                runends: 0,
-                counts: 0,
-                remainders: [0; 64]
-            });
-        }
-        CQF { 
-            lognslots: lognslots,
-            nslots: nslots,
-            xnslots: xnslots,
-            nblocks: nblocks,
-            quotient_bits: key_bits, 
-            remainder_bits: 64 - key_bits, 
+    pub fn try_build(lognslots: u64, key_bits: u64, hash_mode: HashMode) -> Result<Self, CqfError> {
+        CQFOptions::new(lognslots).key_bits(key_bits).hash_mode(hash_mode).build()
+    }
+
+    pub(crate) fn new_table(lognslots: u64, quotient_bits: u64, remainder_bits: u64, value_bits: u64, hash_mode: HashMode) -> Result<Self, CqfError> {
+        let mut qf = Self::new_layout(lognslots, quotient_bits, remainder_bits, value_bits, hash_mode)?;
+        let nwords = qf.nblocks.checked_mul(qf.slot_bits()).ok_or(CqfError::AllocationFailure)?;
+        qf.blocks = Storage::owned(try_alloc(qf.nblocks, Block::default())?);
+        qf.slots = Storage::owned(try_alloc(nwords, 0)?);
+        Ok(qf)
+    }
+
+    // the parameters of a table without the table itself, for one that's only ever streamed to a file
+    pub(crate) fn new_layout(lognslots: u64, quotient_bits: u64, remainder_bits: u64, value_bits: u64, hash_mode: HashMode) -> Result<Self, CqfError> {
+        if lognslots >= 64 {
+            return Err(CqfError::InvalidParameters("lognslots must be less than 64"));
+        }
+        // quotients past nslots would land outside the table
+        if quotient_bits > lognslots {
+            return Err(CqfError::InvalidParameters("quotient bits can't be more than lognslots"));
+        }
+        // counters are written in base 2^r - 2, which needs at least two bits per slot
+        if remainder_bits < 2 {
+            return Err(CqfError::InvalidParameters("remainders must be at least 2 bits"));
+        }
+        // a hash is split by shifting the remainder off, which can't be done 64 bits at a time
+        if remainder_bits >= 64 {
+            return Err(CqfError::InvalidParameters("remainders must be narrower than 64 bits"));
+        }
+        if quotient_bits + remainder_bits > 64 {
+            return Err(CqfError::InvalidParameters("quotient and remainder must fit in a 64-bit hash"));
+        }
+        if remainder_bits + value_bits > 64 {
+            return Err(CqfError::InvalidParameters("remainder and value must fit in a 64-bit slot"));
+        }
+        let xnslots = xnslots_for(lognslots);
+        Ok(CQF {
+            lognslots,
+            nslots: 1 << lognslots,
+            xnslots,
+            nblocks: xnslots.div_ceil(64),
+            quotient_bits,
+            remainder_bits,
+            value_bits,
             hash_mode,
-            blocks: blockvec,
             ..Default::default()
-        }
+        })
     }
 
-    pub fn from(qf1: Self, qf2: Self) -> Self {
-        let lognslots = u64::next_power_of_two(qf1.noccupied_slots + qf2.noccupied_slots).ilog2() as u64;
-        let nslots = 1 << lognslots;
-        assert_eq!(qf1.hash_mode, qf2.hash_mode, "CQFs must have the same hash mode!");
-        let xnslots: u64 = (nslots as f32 + 10.0*((nslots as f32).sqrt())) as u64;
-        let nblocks = (xnslots + 63) / 64;
-        let mut blockvec: Vec<Block> = Vec::with_capacity(nblocks.try_into().unwrap());
-        for _ in 0..nblocks {
-            blockvec.push(Block {
-                offset: 0,
-                occupieds: 0,
-                runends: 0,
-                counts: 0,
-                remainders: [0; 64]
-            });
-        }
-        let mut new = Self { 
-            lognslots: lognslots,
-            nslots: nslots,
-            xnslots: xnslots,
-            nblocks: nblocks,
-            quotient_bits: lognslots, 
-            remainder_bits: 64 - lognslots, 
-            hash_mode: qf1.hash_mode,
-            blocks: blockvec,
-            ..Default::default()
-        };
-        let merged = qf1.into_iter().merge(qf2.into_iter());
-        for item in merged {
-            new.insert_by_hash(item.hash, item.count).expect("couldn't insert into new CQF!");
-        }
-        new
-    }
-
-    pub fn from_multi(qfs: Vec<&Self>) -> Self {
-        let lognslots = u64::next_power_of_two(qfs.iter().map(|qf| qf.noccupied_slots).sum()).ilog2() as u64;
-        let nslots = 1 << lognslots;
-        
-        let first = qfs[0];
-        assert!(qfs.iter().all(|&item| item.hash_mode == first.hash_mode), "all qfs must have the same hash mode!");
-
-        let xnslots: u64 = (nslots as f32 + 10.0*((nslots as f32).sqrt())) as u64;
-        let nblocks = (xnslots + 63) / 64;
-        let mut blockvec: Vec<Block> = Vec::with_capacity(nblocks.try_into().unwrap());
-        for _ in 0..nblocks {
-            blockvec.push(Block {
-                offset: 0,
-                occupieds: 0,
-                runends: 0,
-                counts: 0,
-                remainders: [0; 64]
-            });
-        }
-        let mut new = Self { 
-            lognslots: lognslots,
-            nslots: nslots,
-            xnslots: xnslots,
-            nblocks: nblocks,
-            quotient_bits: lognslots, 
-            remainder_bits: 64 - lognslots, 
-            hash_mode: qfs[0].hash_mode,
-            blocks: blockvec,
-            ..Default::default()
-        };
-        let merged = qfs.into_iter().kmerge();
-        for item in merged {
-            new.insert_by_hash(item.hash, item.count).expect("couldn't insert into new CQF!");
-        }
-        new
-    }
-
-    pub fn resize(&mut self, lognslots: u64, key_bits: u64) {
-        let nslots = 1 << lognslots;
-        assert_eq!(nslots.popcnt(), 1, "nslots must be a power of 2!");
-        let xnslots: u64 = (nslots as f32 + 10.0*((nslots as f32).sqrt())) as u64;
-        let nblocks = (xnslots + 63) / 64;
-        let mut blockvec: Vec<Block> = Vec::with_capacity(nblocks.try_into().unwrap());
-        for _ in 0..nblocks {
-            blockvec.push(Block {
-                offset: 0,
-                occupieds: 0,
-                runends: 0,
-                counts: 0,
-                remainders: [0; 64]
-            });
-        }
-        let mut new = Self { 
-            lognslots: lognslots,
-            nslots: nslots,
-            xnslots: xnslots,
-            nblocks: nblocks,
-            quotient_bits: key_bits, 
-            remainder_bits: 64 - key_bits, 
-            hash_mode: self.hash_mode,
-            blocks: blockvec,
-            ..Default::default()
+    pub fn try_merge(qfs: &[&Self]) -> Result<Self, CqfError> {
+        let first = Self::check_compatible(qfs)?;
+        let noccupied: u64 = qfs.iter().map(|qf| qf.noccupied_slots).sum();
+        // the same key in several filters comes out of the merge together, and is combined as it goes in
+        first.build_sorted(noccupied, || qfs.iter().map(|qf| qf.into_iter()).kmerge().map(Ok))
+    }
+
+    // merges the filters, handing each key's counts (one from each filter that has it) to combiner
+    // for its count in the merged filter. Keys it returns None for are left out
+    pub fn merge_with<F: Fn(&[u64]) -> Option<u64>>(qfs: &[&Self], combiner: F) -> Result<Self, CqfError> {
+        let noccupied: u64 = qfs.iter().map(|qf| qf.noccupied_slots).sum();
+        Self::combine(qfs, noccupied, combiner)
+    }
+
+    // the keys in every one of the filters, each with its smallest count
+    pub fn intersect(qfs: &[&Self]) -> Result<Self, CqfError> {
+        let noccupied = qfs.iter().map(|qf| qf.noccupied_slots).min().unwrap_or_default();
+        // a filter holds each hash once, so a key is in all of them when all of them hand it over
+        Self::combine(qfs, noccupied, |counts| counts.iter().copied().min().filter(|_| counts.len() == qfs.len()))
+    }
+
+    fn combine<F: Fn(&[u64]) -> Option<u64>>(qfs: &[&Self], noccupied: u64, combiner: F) -> Result<Self, CqfError> {
+        let first = Self::check_compatible(qfs)?;
+        let combiner = &combiner;
+        first.build_sorted(noccupied, || {
+            let mut merged = qfs.iter().map(|qf| qf.into_iter()).kmerge().peekable();
+            let mut counts = Vec::with_capacity(qfs.len());
+            std::iter::from_fn(move || loop {
+                let item = merged.next()?;
+                let mut value = Ok(item.value);
+                counts.clear();
+                counts.push(item.count);
+                while let Some(next) = merged.next_if(|next| next.hash == item.hash) {
+                    value = value.and_then(|value| first.value_merge.apply(value, next.value));
+                    counts.push(next.count);
+                }
+                if let Some(count) = combiner(&counts) {
+                    return Some(value.map(|value| FilterItem { value, count, ..item }));
+                }
+            })
+        })
+    }
+
+    // the keys in this filter that aren't in the other one, with the counts they have here
+    pub fn difference(&self, other: &Self) -> Result<Self, CqfError> {
+        Self::check_compatible(&[self, other])?;
+        self.build_sorted(self.noccupied_slots, || {
+            self.into_iter().merge_join_by(other, |a, b| a.hash.cmp(&b.hash)).filter_map(|item| match item {
+                EitherOrBoth::Left(item) => Some(Ok(item)),
+                _ => None,
+            })
+        })
+    }
+
+    // this filter's counts less the other's, dropping keys that get down to 0
+    pub fn subtract(&self, other: &Self) -> Result<Self, CqfError> {
+        Self::check_compatible(&[self, other])?;
+        self.build_sorted(self.noccupied_slots, || {
+            self.into_iter().merge_join_by(other, |a, b| a.hash.cmp(&b.hash)).filter_map(|item| match item {
+                EitherOrBoth::Left(item) => Some(Ok(item)),
+                EitherOrBoth::Both(item, removed) => Some(Ok(FilterItem { count: item.count.saturating_sub(removed.count), ..item })),
+                EitherOrBoth::Right(_) => None,
+            })
+        })
+    }
+
+    // builds a filter straight from items in hash order, like a CQFIterator hands them out, by
+    // writing each one just past the last instead of inserting it. Items with the same hash are
+    // added together
+    pub fn from_sorted_iter<I: IntoIterator<Item = FilterItem>>(lognslots: u64, key_bits: u64, hash_mode: HashMode, items: I) -> Result<Self, CqfError> {
+        let mut qf = Self::try_build(lognslots, key_bits, hash_mode)?;
+        qf.fill_sorted(items.into_iter().map(Ok))?;
+        Ok(qf)
+    }
+
+    // filters only line up if their items hash to the same values and carry the same values
+    pub(crate) fn check_compatible<'a>(qfs: &[&'a Self]) -> Result<&'a Self, CqfError> {
+        let Some(&first) = qfs.first() else {
+            return Err(CqfError::InvalidParameters("there must be at least one CQF to combine"));
         };
-        for item in self.into_iter() {
-            new.insert_by_hash(item.hash, item.count).expect("couldn't insert into new CQF!");
+        if qfs.iter().any(|qf| qf.hash_mode != first.hash_mode || qf.seed != first.seed) {
+            return Err(CqfError::HashModeMismatch);
+        }
+        // value bits come out of the hash, so they'd show up as a different width too
+        if qfs.iter().any(|qf| qf.value_bits != first.value_bits) {
+            return Err(CqfError::InvalidParameters("CQFs must have the same value bits to be combined"));
+        }
+        if qfs.iter().any(|qf| qf.hash_bits() != first.hash_bits()) {
+            return Err(CqfError::HashWidthMismatch);
+        }
+        Ok(first)
+    }
+
+    // an empty filter hashing the same way as this one
+    pub(crate) fn empty_like(&self, lognslots: u64, quotient_bits: u64) -> Result<Self, CqfError> {
+        let mut new = Self::new_table(lognslots, quotient_bits, self.hash_bits() - quotient_bits, self.value_bits, self.hash_mode)?;
+        new.copy_settings(self);
+        Ok(new)
+    }
+
+    // the same, but without allocating the table
+    pub(crate) fn layout_like(&self, lognslots: u64, quotient_bits: u64) -> Result<Self, CqfError> {
+        let mut new = Self::new_layout(lognslots, quotient_bits, self.hash_bits() - quotient_bits, self.value_bits, self.hash_mode)?;
+        new.copy_settings(self);
+        Ok(new)
+    }
+
+    fn copy_settings(&mut self, other: &Self) {
+        self.seed = other.seed;
+        self.resize_policy = other.resize_policy;
+        self.resize_hook = other.resize_hook.clone();
+        self.value_merge = other.value_merge;
+    }
+
+    // lays sorted items out in a new filter like this one sized for noccupied slots, going up a
+    // size whenever they turn out not to fit (narrower remainders can make counters longer)
+    pub(crate) fn build_sorted<I, F>(&self, noccupied: u64, items: F) -> Result<Self, CqfError>
+    where
+        I: Iterator<Item = Result<FilterItem, CqfError>>,
+        F: Fn() -> I,
+    {
+        let (mut lognslots, max_lognslots) = self.sorted_lognslots(noccupied);
+        loop {
+            let mut new = self.empty_like(lognslots, lognslots)?;
+            match new.fill_sorted(items()) {
+                Err(CqfError::CapacityExceeded) if lognslots < max_lognslots => lognslots += 1,
+                result => return result.map(|()| new),
+            }
+        }
+    }
+
+    // the size to start laying noccupied sorted slots out at, and the largest that still leaves
+    // 2-bit remainders. Never less than a block, so a near-empty filter still has quotient bits
+    // and its remainders stay narrower than 64 bits
+    pub(crate) fn sorted_lognslots(&self, noccupied: u64) -> (u64, u64) {
+        let max_lognslots = self.hash_bits() - 2;
+        let lognslots = (noccupied.next_power_of_two().ilog2() as u64).max(MIN_SORTED_LOGNSLOTS);
+        (lognslots.min(max_lognslots), max_lognslots)
+    }
+
+    // fills an empty table from items in hash order, each counter going in right after the one
+    // before it, then works out the block offsets in one pass at the end
+    pub(crate) fn fill_sorted<I: Iterator<Item = Result<FilterItem, CqfError>>>(&mut self, items: I) -> Result<(), CqfError> {
+        let mut next_free = 0;
+        for item in self.coalesce_sorted(items) {
+            next_free = self.append_sorted(item?, next_free)?;
         }
+        self.recompute_offsets();
+        Ok(())
+    }
+
+    // checks items are in hash order and fit this filter, combining the ones with the same hash
+    // like a merge would
+    pub(crate) fn coalesce_sorted<I: Iterator<Item = Result<FilterItem, CqfError>>>(&self, items: I) -> impl Iterator<Item = Result<FilterItem, CqfError>> {
+        let max_value = bitmask(self.value_bits);
+        let value_merge = self.value_merge;
+        items.map(move |item| match item {
+            Ok(item) if item.value > max_value => Err(CqfError::InvalidParameters("value doesn't fit in the filter's value bits")),
+            item => item,
+        }).coalesce(move |a, b| match (a, b) {
+            (Ok(a), Ok(b)) if a.hash == b.hash => Ok(value_merge.apply(a.value, b.value).and_then(|value| {
+                let count = a.count.checked_add(b.count).ok_or(CqfError::CountOverflow)?;
+                Ok(FilterItem { value, count, ..a })
+            })),
+            (Ok(a), Ok(b)) if a.hash > b.hash => Ok(Err(CqfError::InvalidParameters("items must be sorted by hash"))),
+            (a, b) => Err((a, b)),
+        })
+    }
+
+    // writes a counter just past everything written so far, returning where the next one can go
+    fn append_sorted(&mut self, item: FilterItem, next_free: usize) -> Result<usize, CqfError> {
+        if item.count == 0 {
+            return Ok(next_free);
+        }
+        let (quotient, remainder) = self.calc_qr(item.hash);
+        let slots = self.encode_counter((remainder << self.value_bits) | item.value, item.count);
+        // a new run starts at its quotient, or straight after the last run if that's further along
+        let continues_run = self.is_occupied(quotient);
+        let start = if continues_run { next_free } else { next_free.max(quotient) };
+        if start + slots.len() > self.xnslots as usize {
+            return Err(CqfError::CapacityExceeded);
+        }
+        if continues_run {
+            self.set_runend(next_free - 1, false);
+        }
+        for (i, &slot) in slots.iter().enumerate() {
+            self.set_slot(start + i, slot);
+        }
+        self.set_runend(start + slots.len() - 1, true);
+        self.set_occupied(quotient, true);
+        self.noccupied_slots += slots.len() as u64;
+        Ok(start + slots.len())
+    }
+
+    // leaves the filter as it was if the new size can't be built or can't hold everything
+    pub fn try_resize(&mut self, lognslots: u64, key_bits: u64) -> Result<(), CqfError> {
+        if key_bits > self.hash_bits() {
+            return Err(CqfError::InvalidParameters("key bits can't be more than the hash width"));
+        }
+        let mut new = self.empty_like(lognslots, key_bits)?;
+        // the items come out in hash order whatever the split, and already fit in this filter
+        new.fill_sorted(self.into_iter().map(Ok))?;
         *self = new;
+        Ok(())
     }
 
-    pub fn serialize(&self, path: PathBuf) -> Result<()> {
-        let mut file = BufWriter::new(File::create(path)?);
-        bincode::encode_into_std_write(self, &mut file, bincode::config::standard())?;
+    // the policy is checked here rather than when it's used, so a bad one never gets as far as an insert
+    pub fn set_resize_policy(&mut self, policy: ResizePolicy) -> Result<(), CqfError> {
+        policy.validate()?;
+        self.resize_policy = policy;
         Ok(())
     }
 
-    pub fn deserialize(path: PathBuf) -> Result<Self> {
-        let mut file = BufReader::new(File::open(path)?);
-        let deserialized: CQF = bincode::decode_from_std_read(&mut file, bincode::config::standard())?;
-        Ok(deserialized)
+    pub fn resize_policy(&self) -> ResizePolicy {
+        self.resize_policy
     }
 
-    fn find_first_empty_slot(&self, mut from: usize) -> usize {
-        loop {
+    // called before and after every resize an insert sets off, from the inserting thread
+    pub fn on_resize<F: Fn(ResizeEvent) + Send + Sync + 'static>(&mut self, hook: F) {
+        self.resize_hook = Some(std::sync::Arc::new(hook));
+    }
+
+    pub(crate) fn can_grow(&self) -> bool {
+        self.resize_policy.growth_bits().is_some_and(|bits| self.can_grow_by(bits))
+    }
+
+    pub(crate) fn can_grow_by(&self, bits: u64) -> bool {
+        self.remainder_bits >= bits + 2 && self.lognslots + bits < 64
+    }
+
+    // how many bits lognslots has to go up by, in steps of the policy's factor, for noccupied_slots
+    // to be under the threshold (or as far as it can go)
+    pub(crate) fn growth_to_fit(&self, noccupied_slots: u64) -> u64 {
+        let ResizePolicy::Grow { threshold, growth_factor } = self.resize_policy else {
+            return 0;
+        };
+        let step = growth_factor.ilog2() as u64;
+        let mut bits = 0;
+        while noccupied_slots as f32 >= threshold * xnslots_for(self.lognslots + bits) as f32 && self.can_grow_by(bits + step) {
+            bits += step;
+        }
+        bits
+    }
+
+    pub(crate) fn should_grow(&self, noccupied_slots: u64) -> bool {
+        match self.resize_policy {
+            ResizePolicy::Grow { threshold, .. } => noccupied_slots as f32 >= threshold * self.xnslots as f32 && self.can_grow(),
+            ResizePolicy::Disabled => false,
+        }
+    }
+
+    // grows the table by the policy's factor, moving bits of every hash from the remainder into the quotient
+    pub(crate) fn grow(&mut self) -> Result<(), CqfError> {
+        if !self.can_grow() {
+            return Err(CqfError::CapacityExceeded);
+        }
+        self.grow_by(self.resize_policy.growth_bits().unwrap_or_default())
+    }
+
+    pub(crate) fn grow_by(&mut self, bits: u64) -> Result<(), CqfError> {
+        let old_nslots = self.nslots;
+        let new_nslots = old_nslots << bits;
+        let start = Instant::now();
+        self.emit(ResizeEvent::Started { old_nslots, new_nslots, load_factor: self.get_load_factor() });
+        self.try_resize(self.lognslots + bits, self.quotient_bits + bits)?;
+        self.emit(ResizeEvent::Finished { old_nslots, new_nslots, elapsed: start.elapsed() });
+        Ok(())
+    }
+
+    pub(crate) fn emit(&self, event: ResizeEvent) {
+        if let Some(hook) = &self.resize_hook {
+            hook(event);
+        }
+    }
+
+    pub fn total_size_in_bytes(&self) -> usize {
+        self.blocks.len() * std::mem::size_of::<Block>() + self.slots.len() * std::mem::size_of::<u64>()
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn value_bits(&self) -> u64 {
+        self.value_bits
+    }
+
+    pub fn set_value_merge(&mut self, merge: ValueMerge) {
+        self.value_merge = merge;
+    }
+
+    pub fn value_merge(&self) -> ValueMerge {
+        self.value_merge
+    }
+
+    pub fn serialize(&self, path: PathBuf) -> Result<(), CqfError> {
+        self.write_to(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn deserialize(path: PathBuf) -> Result<Self, CqfError> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        format::read(&mut BufReader::new(file), Some(len))
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), CqfError> {
+        format::write(self, &mut writer)
+    }
+
+    // reads exactly one filter, so the stream can carry more data after it
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Self, CqfError> {
+        format::read(&mut reader, None)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CqfError> {
+        let mut bytes = Vec::with_capacity(format::serialized_size(self));
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, CqfError> {
+        let len = bytes.len() as u64;
+        format::read(&mut bytes, Some(len))
+    }
+
+    // the reference C CQF's file format, for sharing filters with tools built on it
+    pub fn serialize_c(&self, path: PathBuf) -> Result<(), CqfError> {
+        self.write_c_to(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn deserialize_c(path: PathBuf) -> Result<Self, CqfError> {
+        Self::read_c_from(BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_c_to<W: Write>(&self, mut writer: W) -> Result<(), CqfError> {
+        cformat::write(self, &mut writer)
+    }
+
+    pub fn read_c_from<R: Read>(mut reader: R) -> Result<Self, CqfError> {
+        cformat::read(&mut reader)
+    }
+
+    // works out every block's offset from the runends and occupieds alone, front to back
+    pub(crate) fn recompute_offsets(&mut self) {
+        for block_idx in 0..(self.nblocks as usize).saturating_sub(1) {
+            let last_index = 64 * block_idx + 63;
+            let runend_index = self.run_end(last_index);
+            let offset = if runend_index / 64 == block_idx { 0 } else { (runend_index - last_index) as u64 };
+            self.update_block(block_idx + 1, |block| block.offset = offset);
+        }
+    }
+
+    // anything at or past limit means there's no room (limit is xnslots unless only part of the table is locked)
+    pub(crate) fn find_first_empty_slot(&self, mut from: usize, limit: usize) -> usize {
+        while from < limit {
             let t = self.offset_lower_bound(from);
             if t == 0 {
                 break;
             }
             from += t as usize;
         }
-        return from;
+        from
     }
 
-    fn shift_remainders(&mut self, insert_index: usize, empty_slot_index: usize, distance: usize) {
-        for i in (insert_index..=empty_slot_index).rev() {
-            self.set_slot(i + distance, self.get_slot(i));
+    fn find_next_n_empty_slots(&self, mut from: usize, n: usize, limit: usize) -> Option<Vec<usize>> {
+        let mut empties = Vec::with_capacity(n);
+        for _ in 0..n {
+            let empty = self.find_first_empty_slot(from, limit);
+            if empty >= limit {
+                return None;
+            }
+            empties.push(empty);
+            from = empty + 1;
         }
+        Some(empties)
     }
 
-    fn shift_runends(&mut self, insert_index: usize, empty_slot_index: usize, distance: usize) {
-        for i in (insert_index..=empty_slot_index).rev() {
-            self.set_runend(i + distance, self.is_runend(i));
+    fn shift_remainders(&self, insert_index: usize, empty_slot_index: usize, distance: usize) {
+        if empty_slot_index <= insert_index {
+            return;
+        }
+        // move the packed bits a word at a time, starting from the top so nothing is overwritten before it's read
+        let bits = self.slot_bits() as usize;
+        let src = insert_index * bits;
+        let dst = (insert_index + distance) * bits;
+        let mut remaining = (empty_slot_index - insert_index) * bits;
+        while remaining > 0 {
+            let nbits = remaining.min(64);
+            remaining -= nbits;
+            let chunk = self.read_bits(src + remaining, nbits);
+            self.write_bits(dst + remaining, nbits, chunk);
         }
     }
 
-    fn shift_counts(&mut self, insert_index: usize, empty_slot_index: usize, distance: usize) {
-        for i in (insert_index..=empty_slot_index).rev() {
-            self.set_count(i + distance, self.is_count(i));
+    fn shift_runends(&self, insert_index: usize, empty_slot_index: usize, distance: usize) {
+        for i in (insert_index..empty_slot_index).rev() {
+            self.set_runend(i + distance, self.is_runend(i));
         }
     }
 
-    fn offset_lower_bound(&self, index: usize) -> u64 {
+    pub(crate) fn offset_lower_bound(&self, index: usize) -> u64 {
         let block_idx = index / 64;
         let slot = index as u64 % 64;
         self.get_block(block_idx).offset_lower_bound(slot)
@@ -218,204 +542,531 @@ impl CQF {
         self.noccupied_slots as f32 / self.xnslots as f32
     }
 
-    pub fn check_and_resize(&mut self) {
-        if self.get_load_factor() >= 0.95 {
-            println!("CQF is filling up, resizing...");
-            self.resize(self.lognslots + 1, self.quotient_bits + 1);
-            println!("resize successful!");
+    // a filter that can't grow any more just keeps filling up until it's out of room
+    pub fn check_and_resize(&mut self) -> Result<(), CqfError> {
+        if self.should_grow(self.noccupied_slots) {
+            self.grow()?;
         }
+        Ok(())
     }
 
-    pub fn insert(&mut self, item: u64, count: u64) -> Result<()> {
-        self.check_and_resize();
-
+    pub fn insert(&mut self, item: u64, count: u64) -> Result<(), CqfError> {
         let hash = self.calc_hash(item);
         self.insert_by_hash(hash, count)
     }
 
-    pub fn insert_by_hash(&mut self, hash: u64, count: u64) -> Result<()> {
-        self.check_and_resize();
+    // keys that are already in a key-value filter keep their value, new ones get 0
+    pub fn insert_by_hash(&mut self, hash: u64, count: u64) -> Result<(), CqfError> {
+        self.check_and_resize()?;
+        self.insert_with_value(hash, 0, count)
+    }
 
-        let (quotient, remainder) = self.calc_qr(hash);
-        let runend_index = self.run_end(quotient);
+    pub fn insert_kv(&mut self, item: u64, value: u64, count: u64) -> Result<(), CqfError> {
+        let hash = self.calc_hash(item);
+        self.insert_kv_by_hash(hash, value, count)
+    }
+
+    pub fn insert_kv_key<K: CqfKey + ?Sized>(&mut self, key: &K, value: u64, count: u64) -> Result<(), CqfError> {
+        let hash = self.hash_key(key);
+        self.insert_kv_by_hash(hash, value, count)
+    }
+
+    // a key that's already there has its value combined with this one by the filter's ValueMerge
+    pub fn insert_kv_by_hash(&mut self, hash: u64, value: u64, count: u64) -> Result<(), CqfError> {
+        if value > bitmask(self.value_bits) {
+            return Err(CqfError::InvalidParameters("value doesn't fit in the filter's value bits"));
+        }
+        self.check_and_resize()?;
+        if let Some((old_value, old_count)) = self.query_value_by_hash(hash) {
+            let merged = self.value_merge.apply(old_value, value)?;
+            if merged != old_value {
+                // the value is part of the slot, so the counter has to be written out again
+                let total = old_count.checked_add(count).ok_or(CqfError::CountOverflow)?;
+                return self.rewrite_counter(hash, merged, total);
+            }
+        }
+        self.insert_with_value(hash, value, count)
+    }
+
+    // gives a key that's already there a new value and count by writing its counter out again
+    // where it is, so running out of room leaves the filter as it was
+    fn rewrite_counter(&mut self, hash: u64, value: u64, count: u64) -> Result<(), CqfError> {
+        loop {
+            let (quotient, remainder) = self.calc_qr(hash);
+            let mut runstart_index = if quotient == 0 { 0 } else { self.run_end(quotient - 1) + 1 }.max(quotient);
+            let (mut current_slot, mut current_count): (u64, u64) = (0, 0);
+            let mut current_end = self.decode_counter(runstart_index, &mut current_slot, &mut current_count);
+            while current_slot >> self.value_bits != remainder {
+                runstart_index = current_end + 1;
+                current_end = self.decode_counter(runstart_index, &mut current_slot, &mut current_count);
+            }
 
-        if self.might_be_empty(quotient) && runend_index == quotient {
+            let old_length = current_end - runstart_index + 1;
+            let slots = self.encode_counter((remainder << self.value_bits) | value, count);
+            // the digits skip the new value's slot instead of the old one's, which can drop the escape
+            if slots.len() < old_length {
+                self.remove_and_shift(false, quotient, runstart_index, &slots, old_length);
+                self.noccupied_slots -= (old_length - slots.len()) as u64;
+                return Ok(());
+            }
+            let operation = if self.is_runend(current_end) { 1 } else { 2 };
+            match self.insert_and_shift(operation, quotient, runstart_index, &slots, old_length, self.xnslots as usize) {
+                Some(nslots) => {
+                    self.noccupied_slots += nslots;
+                    return Ok(());
+                },
+                None => self.grow()?,
+            }
+        }
+    }
+
+    pub(crate) fn insert_with_value(&mut self, hash: u64, value: u64, count: u64) -> Result<(), CqfError> {
+        loop {
+            // SAFETY: &mut self
+            match unsafe { self.insert_within(hash, value, count, self.xnslots as usize) }? {
+                Some(nslots) => {
+                    self.noccupied_slots += nslots;
+                    return Ok(());
+                },
+                // a long counter near the end of the table can need more room than the load factor left
+                None => self.grow()?,
+            }
+        }
+    }
+
+    // inserts without resizing or touching noccupied_slots, only using empty slots below limit.
+    // A key that's already there keeps the value it has. Returns how many slots the insert took
+    // up, or None without changing anything if it needs an empty slot at or past limit.
+    // SAFETY: the caller needs either &mut self, or the region locks over everything from the
+    // region before the quotient's up to limit
+    pub(crate) unsafe fn insert_within(&self, hash: u64, value: u64, count: u64, limit: usize) -> Result<Option<u64>, CqfError> {
+        if count == 0 {
+            return Ok(Some(0));
+        }
+
+        let (quotient, remainder) = self.calc_qr(hash);
+        let slot = (remainder << self.value_bits) | value;
+        if count == 1 && self.might_be_empty(quotient) && self.run_end(quotient) == quotient {
             self.set_runend(quotient, true);
-            self.set_slot(quotient, remainder);
+            self.set_slot(quotient, slot);
             self.set_occupied(quotient, true);
-            self.noccupied_slots += 1;
-            if count > 1 {
-                self.insert_by_hash(hash, count - 1)?;
-            }
+            return Ok(Some(1));
+        }
+
+        let mut runstart_index = if quotient == 0 { 0 } else { self.run_end(quotient - 1) + 1 };
+        let inserted = if !self.is_occupied(quotient) {
+            let slots = self.encode_counter(slot, count);
+            self.insert_and_shift(0, quotient, runstart_index, &slots, 0, limit)
         } else {
-            let mut runstart_index = if quotient == 0 { 0 } else { self.run_end(quotient - 1) + 1 };
-            if !self.is_occupied(quotient) {
-                self.insert_and_shift(0, quotient, remainder, count, runstart_index, 0);
-            } else {
-                let (mut current_remainder, mut current_count): (u64, u64) = (0, 0);
-                let mut current_end: usize;
-                current_end = self.decode_counter(runstart_index, &mut current_remainder, &mut current_count);
-                while current_remainder < remainder && !self.is_runend(current_end) {
-                    runstart_index = current_end + 1;
-                    current_end = self.decode_counter(runstart_index, &mut current_remainder, &mut current_count)
-                }
+            let (mut current_slot, mut current_count): (u64, u64) = (0, 0);
+            let mut current_end: usize;
+            current_end = self.decode_counter(runstart_index, &mut current_slot, &mut current_count);
+            while current_slot >> self.value_bits < remainder && !self.is_runend(current_end) {
+                runstart_index = current_end + 1;
+                current_end = self.decode_counter(runstart_index, &mut current_slot, &mut current_count)
+            }
 
-                if current_remainder < remainder {
-                    self.insert_and_shift(1, quotient, remainder, count, current_end + 1, 0);
-                } else if current_remainder == remainder {
-                    self.insert_and_shift(if self.is_runend(current_end) { 1 } else { 2 }, quotient, remainder, current_count + count, runstart_index, current_end - runstart_index + 1);
-                } else {
-                    self.insert_and_shift(2, quotient, remainder, count, runstart_index, 0);
-                }
+            if current_slot >> self.value_bits < remainder {
+                let slots = self.encode_counter(slot, count);
+                self.insert_and_shift(1, quotient, current_end + 1, &slots, 0, limit)
+            } else if current_slot >> self.value_bits == remainder {
+                let Some(new_count) = current_count.checked_add(count) else {
+                    return Err(CqfError::CountOverflow);
+                };
+                let slots = self.encode_counter(current_slot, new_count);
+                self.insert_and_shift(if self.is_runend(current_end) { 1 } else { 2 }, quotient, runstart_index, &slots, current_end - runstart_index + 1, limit)
+            } else {
+                let slots = self.encode_counter(slot, count);
+                self.insert_and_shift(2, quotient, runstart_index, &slots, 0, limit)
             }
+        };
+
+        if inserted.is_some() {
             self.set_occupied(quotient, true);
         }
-
-        Ok(())
+        Ok(inserted)
     }
 
-    fn insert_and_shift(&mut self, operation: u64, quotient: usize, remainder: u64, count: u64, insert_index: usize, noverwrites: usize) {
-        let ninserts = if count == 1 { 1 } else { 2 } - noverwrites;
+    // writes `slots` at overwrite_index, replacing the first noverwrites slots already there and
+    // shifting everything after them up to make room for the rest. Returns how many new slots were
+    // used, or None without touching anything if there aren't enough empty slots below limit
+    fn insert_and_shift(&self, operation: u64, quotient: usize, overwrite_index: usize, slots: &[u64], noverwrites: usize, limit: usize) -> Option<u64> {
+        let total = slots.len();
+        let ninserts = total.saturating_sub(noverwrites);
         if ninserts > 0 {
-            match ninserts {
-                1 => {
-                    let empty = self.find_first_empty_slot(insert_index);
-                    self.shift_remainders(insert_index, empty - 1, 1);
-                    self.shift_runends(insert_index, empty - 1, 1);
-                    self.shift_counts(insert_index, empty - 1, 1);
-                    for i in (((quotient / 64) + 1)..).take_while(|i: &usize| *i <= empty / 64) {
-                        if empty / 64 < i {
-                            break;
-                        }
-
-                        self.get_block_mut(i).offset += 1;
-                    }
-                },
-                2 => {
-                    let first = self.find_first_empty_slot(insert_index);
-                    let second = self.find_first_empty_slot(first + 1);
-                    self.shift_remainders(first + 1, second - 1, 1);
-                    self.shift_runends(first + 1, second - 1, 1);
-                    self.shift_counts(first + 1, second - 1, 1);
-                    self.shift_remainders(insert_index, first - 1, 2);
-                    self.shift_runends(insert_index, first - 1, 2);
-                    self.shift_counts(insert_index, first - 1, 2);
-
-                    let mut npreceding_empties = 0;
-                    for i in (((quotient / 64) + 1)..).take_while(|i: &usize| *i <= second / 64) {
-                        if npreceding_empties == 0 && first / 64 < i {
-                            npreceding_empties += 1;
-                        }
-                        if npreceding_empties == 1 && second / 64 < i {
-                            break;
-                        }
-
-                        self.get_block_mut(i).offset += (ninserts - npreceding_empties) as u16;
-                    }
-                },
-                _ => panic!("unexpected number of inserts!")
+            let insert_index = overwrite_index + noverwrites;
+            let empties = self.find_next_n_empty_slots(insert_index, ninserts, limit)?;
+            // everything between two empty slots moves up by the number of empties above it
+            for i in (1..ninserts).rev() {
+                self.shift_remainders(empties[i - 1] + 1, empties[i], ninserts - i);
+                self.shift_runends(empties[i - 1] + 1, empties[i], ninserts - i);
             }
+            self.shift_remainders(insert_index, empties[0], ninserts);
+            self.shift_runends(insert_index, empties[0], ninserts);
 
+            for i in noverwrites..total - 1 {
+                self.set_runend(overwrite_index + i, false);
+            }
             match operation {
                 0 => {
-                    if count == 1 {
-                        self.set_runend(insert_index, true);
-                    } else {
-                        self.set_runend(insert_index, false);
-                        self.set_runend(insert_index + 1, true);
-                    }
+                    self.set_runend(overwrite_index + total - 1, true);
                 },
                 1 => {
-                    if noverwrites == 0 {
-                        self.set_runend(insert_index - 1, false);
-                    }
-                    if count == 1 {
-                        self.set_runend(insert_index, true);
-                    } else {
-                        self.set_runend(insert_index, false);
-                        self.set_runend(insert_index + 1, true);
-                    }
+                    self.set_runend(overwrite_index + noverwrites - 1, false);
+                    self.set_runend(overwrite_index + total - 1, true);
                 },
                 2 => {
-                    if count == 1 {
-                        self.set_runend(insert_index, false);
-                    } else {
-                        self.set_runend(insert_index, false);
-                        self.set_runend(insert_index + 1, false);
-                    }
+                    self.set_runend(overwrite_index + total - 1, false);
                 },
                 _ => panic!("invalid operation!"),
             }
+
+            let mut npreceding_empties = 0;
+            for i in ((quotient / 64) + 1)..=(empties[ninserts - 1] / 64) {
+                while npreceding_empties < ninserts && empties[npreceding_empties] / 64 < i {
+                    npreceding_empties += 1;
+                }
+                self.update_block(i, |block| block.offset += (ninserts - npreceding_empties) as u64);
+            }
+        }
+
+        for (i, &slot) in slots.iter().enumerate() {
+            self.set_slot(overwrite_index + i, slot);
+        }
+        Some(ninserts as u64)
+    }
+
+    pub fn remove(&mut self, item: u64, count: u64) -> Result<u64, CqfError> {
+        let hash = self.calc_hash(item);
+        self.remove_by_hash(hash, count)
+    }
+
+    pub fn delete_all(&mut self, item: u64) -> Result<u64, CqfError> {
+        self.remove(item, u64::MAX)
+    }
+
+    pub fn remove_by_hash(&mut self, hash: u64, count: u64) -> Result<u64, CqfError> {
+        // SAFETY: &mut self
+        let (removed, nfreed) = unsafe { self.remove_in_place(hash, count) };
+        self.noccupied_slots -= nfreed;
+        Ok(removed)
+    }
+
+    // removes without touching noccupied_slots, returning how much was removed and how many slots that freed.
+    // SAFETY: the caller needs either &mut self, or the region locks over the quotient's cluster
+    // and the region after it
+    pub(crate) unsafe fn remove_in_place(&self, hash: u64, count: u64) -> (u64, u64) {
+        let (quotient, remainder) = self.calc_qr(hash);
+        if count == 0 || !self.is_occupied(quotient) {
+            return (0, 0);
+        }
+
+        let mut runstart_index = if quotient == 0 { 0 } else { self.run_end(quotient - 1) + 1 };
+        if runstart_index < quotient {
+            runstart_index = quotient;
+        }
+        let first_index = runstart_index;
+        let (mut current_slot, mut current_count): (u64, u64) = (0, 0);
+        let mut current_end = self.decode_counter(runstart_index, &mut current_slot, &mut current_count);
+        while current_slot >> self.value_bits < remainder && !self.is_runend(current_end) {
+            runstart_index = current_end + 1;
+            current_end = self.decode_counter(runstart_index, &mut current_slot, &mut current_count);
+        }
+        if current_slot >> self.value_bits != remainder {
+            return (0, 0);
+        }
+
+        let removed = count.min(current_count);
+        let only_item_in_run = runstart_index == first_index && self.is_runend(current_end);
+        let slots = self.encode_counter(current_slot, current_count - removed);
+        self.remove_and_shift(only_item_in_run, quotient, runstart_index, &slots, current_end - runstart_index + 1);
+        (removed, (current_end - runstart_index + 1 - slots.len()) as u64)
+    }
+
+    // writes `slots` over the old_length slots at overwrite_index and shifts the rest of the
+    // cluster back over whatever is left; counters never grow when their count goes down
+    fn remove_and_shift(&self, only_item_in_run: bool, quotient: usize, overwrite_index: usize, slots: &[u64], old_length: usize) {
+        let new_length = slots.len();
+        debug_assert!(new_length <= old_length, "a smaller count can't take more slots!");
+        let was_runend = self.is_runend(overwrite_index + old_length - 1);
+
+        for (i, &slot) in slots.iter().enumerate() {
+            self.set_slot(overwrite_index + i, slot);
+        }
+
+        // if this was the last thing in its run, the runend has to move back with it
+        if was_runend {
+            if new_length > 0 {
+                self.set_runend(overwrite_index + new_length - 1, true);
+            } else if overwrite_index > quotient && !self.is_runend(overwrite_index - 1) {
+                self.set_runend(overwrite_index - 1, true);
+            }
+        }
+
+        // shift the rest of the cluster back one run at a time, stopping runs at their quotient
+        let mut current_bucket = quotient;
+        let mut current_slot = overwrite_index + new_length;
+        let mut current_distance = old_length - new_length;
+        while current_distance > 0 {
+            if self.is_runend(current_slot + current_distance - 1) {
+                loop {
+                    current_bucket += 1;
+                    if current_bucket >= current_slot + current_distance || self.is_occupied(current_bucket) {
+                        break;
+                    }
+                }
+            }
+
+            if current_bucket <= current_slot {
+                self.set_slot(current_slot, self.get_slot(current_slot + current_distance));
+                self.set_runend(current_slot, self.is_runend(current_slot + current_distance));
+                current_slot += 1;
+            } else if current_bucket <= current_slot + current_distance {
+                for i in current_slot..current_slot + current_distance {
+                    self.set_slot(i, 0);
+                    self.set_runend(i, false);
+                }
+                current_distance = current_slot + current_distance - current_bucket;
+                current_slot = current_bucket;
+            } else {
+                current_distance = 0;
+            }
         }
-        
-        self.set_slot(insert_index, remainder);
-        if count != 1 {
-            // if the count isn't one, put a count in the next slot
-            self.set_count(insert_index + 1, true);
-            self.set_slot(insert_index + 1, count);
+
+        if only_item_in_run && new_length == 0 {
+            self.set_occupied(quotient, false);
+        }
+
+        // fix up the offsets of every block the shifted cluster ran through
+        if old_length > new_length {
+            let mut block_idx = quotient / 64;
+            while block_idx + 1 < self.nblocks as usize {
+                let last_index = 64 * block_idx + 63;
+                let runend_index = self.run_end(last_index);
+                let offset = if runend_index / 64 == block_idx { 0 } else { (runend_index - last_index) as u64 };
+                if self.get_block(block_idx + 1).offset == offset {
+                    break;
+                }
+                self.update_block(block_idx + 1, |block| block.offset = offset);
+                block_idx += 1;
+            }
         }
-        self.noccupied_slots += ninserts as u64;
     }
 
     pub fn query(&self, item: u64) -> u64 {
         self.query_by_hash(self.calc_hash(item))
     }
 
+    pub fn insert_key<K: CqfKey + ?Sized>(&mut self, key: &K, count: u64) -> Result<(), CqfError> {
+        let hash = self.hash_key(key);
+        self.insert_by_hash(hash, count)
+    }
+
+    pub fn remove_key<K: CqfKey + ?Sized>(&mut self, key: &K, count: u64) -> Result<u64, CqfError> {
+        let hash = self.hash_key(key);
+        self.remove_by_hash(hash, count)
+    }
+
+    pub fn query_key<K: CqfKey + ?Sized>(&self, key: &K) -> u64 {
+        self.query_by_hash(self.hash_key(key))
+    }
+
+    pub fn hash_key<K: CqfKey + ?Sized>(&self, key: &K) -> u64 {
+        if let Some(item) = key.as_u64() {
+            return self.calc_hash(item);
+        }
+        let input = key::hash_input(key);
+        match self.hash_mode {
+            HashMode::Fast => xxh3_64_with_seed(&input, self.seed) & bitmask(self.hash_bits()),
+            HashMode::Murmur => murmur64a(&input, self.seed as u32) & bitmask(self.hash_bits()),
+            // wider keys can't be handed back anyway, so fold them to 64 bits and hash that
+            HashMode::None | HashMode::Invertible => self.calc_hash(xxh3_64_with_seed(&input, self.seed)),
+        }
+    }
+
     pub fn query_by_hash(&self, hash: u64) -> u64 {
+        self.query_value_by_hash(hash).map_or(0, |(_, count)| count)
+    }
+
+    // the value stored with the item and its count, or None if it isn't there
+    pub fn query_value(&self, item: u64) -> Option<(u64, u64)> {
+        self.query_value_by_hash(self.calc_hash(item))
+    }
+
+    pub fn query_value_key<K: CqfKey + ?Sized>(&self, key: &K) -> Option<(u64, u64)> {
+        self.query_value_by_hash(self.hash_key(key))
+    }
+
+    pub fn query_value_by_hash(&self, hash: u64) -> Option<(u64, u64)> {
         let (quotient, remainder) = self.calc_qr(hash);
         if !self.is_occupied(quotient) {
-            return 0;
+            return None;
         }
         let mut runstart_index = if quotient == 0 { 0 } else { self.run_end(quotient - 1) + 1 };
         if runstart_index < quotient {
             runstart_index = quotient;
         }
         let mut current_end: usize;
-        let mut current_remainder: u64 = 0;
+        let mut current_slot: u64 = 0;
         let mut current_count: u64 = 0;
         loop {
-            current_end = self.decode_counter(runstart_index, &mut current_remainder, &mut current_count);
-            if current_remainder == remainder {
-                return current_count;
+            current_end = self.decode_counter(runstart_index, &mut current_slot, &mut current_count);
+            if current_slot >> self.value_bits == remainder {
+                return Some((current_slot & bitmask(self.value_bits), current_count));
             }
             if self.is_runend(current_end) { break; }
             runstart_index = current_end + 1;
         }
-        return 0;
+        None
+    }
+
+    // counters follow the CQF paper: a remainder x with count 1 is "x", count 2 is "x x", and
+    // anything larger is x, the count as digits in base 2^r - 2 (2^r - 1 when x is 0), then x
+    // again. Digits skip the values 0 and x, and a 0 escape goes in front of the digits when the
+    // first one would otherwise be >= x, so a counter can never be mistaken for the next remainder
+    pub(crate) fn encode_counter(&self, remainder: u64, count: u64) -> Vec<u64> {
+        // built back to front, then flipped
+        let mut slots = Vec::new();
+        if count == 0 {
+            return slots;
+        }
+        slots.push(remainder);
+        if count == 1 {
+            return slots;
+        }
+        if count == 2 {
+            slots.push(remainder);
+            return slots;
+        }
+        if count == 3 {
+            slots.push(0);
+            slots.push(remainder);
+            return slots;
+        }
+
+        let mut base = bitmask(self.slot_bits());
+        let mut counter = count;
+        if remainder == 0 {
+            slots.push(0);
+            counter -= 4;
+        } else {
+            base -= 1;
+            counter -= 3;
+        }
+        let mut digit;
+        loop {
+            digit = counter % base + 1;
+            if remainder != 0 && digit >= remainder {
+                digit += 1;
+            }
+            slots.push(digit);
+            counter /= base;
+            if counter == 0 {
+                break;
+            }
+        }
+        if remainder != 0 && digit >= remainder {
+            slots.push(0);
+        }
+        slots.push(remainder);
+        slots.reverse();
+        slots
     }
 
     fn decode_counter(&self, index: usize, remainder: &mut u64, count: &mut u64) -> usize {
-        *remainder = self.get_slot(index);
+        self.decode_with(index, |i| self.get_slot(i), |i| self.is_runend(i), remainder, count)
+    }
 
-        // if it's a runend or the next thing is not a count, there's only one
-        if self.is_runend(index) || !self.is_count(index + 1) {
+    // decodes a counter out of a run read in on its own, which ends with the run's runend
+    pub(crate) fn decode_run(&self, run: &[u64], index: usize, remainder: &mut u64, count: &mut u64) -> usize {
+        self.decode_with(index, |i| run[i], |i| i + 1 == run.len(), remainder, count)
+    }
+
+    // decoding only ever looks at slots up to the end of the counter's run
+    fn decode_with<S: Fn(usize) -> u64, E: Fn(usize) -> bool>(&self, index: usize, get_slot: S, is_runend: E, remainder: &mut u64, count: &mut u64) -> usize {
+        let rem = get_slot(index);
+        *remainder = rem;
+
+        // a runend holds a lone remainder
+        if is_runend(index) {
             *count = 1;
-            return index; 
-        } else { // otherwise, whatever is in the next slot is the count
-            *count = self.get_slot(index + 1);
-            return index + 1;
+            return index;
+        }
+
+        let mut digit = get_slot(index + 1);
+        // anything >= the remainder that follows it is either a repeat of it or the next remainder
+        if is_runend(index + 1) || (rem > 0 && digit >= rem) {
+            *count = if digit == rem { 2 } else { 1 };
+            return if digit == rem { index + 1 } else { index };
+        }
+        if rem > 0 && digit == 0 && get_slot(index + 2) == rem {
+            *count = 3;
+            return index + 2;
+        }
+        if rem == 0 && digit == 0 {
+            if get_slot(index + 2) == 0 {
+                *count = 3;
+                return index + 2;
+            } else {
+                *count = 2;
+                return index + 1;
+            }
+        }
+
+        let base = bitmask(self.slot_bits()) - if rem > 0 { 1 } else { 0 };
+        let mut cnt: u64 = 0;
+        let mut end = index + 1;
+        while digit != rem && !is_runend(end) {
+            if digit > rem {
+                digit -= 1;
+            }
+            if digit > 0 && rem > 0 {
+                digit -= 1;
+            }
+            // a lone 0 remainder reads the rest of its run as digits before finding out they aren't,
+            // so this can wrap; the count is thrown away in that case
+            cnt = cnt.wrapping_mul(base).wrapping_add(digit);
+            end += 1;
+            digit = get_slot(end);
         }
+
+        if rem > 0 {
+            *count = cnt + 3;
+            return end;
+        }
+        // a 0 remainder's counter is closed by two 0s, otherwise the 0 stood alone
+        if is_runend(end) || get_slot(end + 1) != 0 {
+            *count = 1;
+            return index;
+        }
+        *count = cnt + 4;
+        end + 1
     }
 
-    fn calc_hash(&self, item: u64) -> u64 {
+    pub(crate) fn calc_hash(&self, item: u64) -> u64 {
+        // hashes narrower than 64 bits are masked at every step so they stay invertible
+        let mask = bitmask(self.hash_bits());
         match self.hash_mode {
-            HashMode::None => item,
+            HashMode::None => item & mask,
             HashMode::Invertible => {
                 let mut key = item;
-                key = (!key).wrapping_add(key << 21); // key = (key << 21) - key - 1;
+                key = (!key).wrapping_add(key << 21) & mask; // key = (key << 21) - key - 1;
                 key = key ^ (key >> 24);
-                key = (key.wrapping_add(key << 3)).wrapping_add(key << 8); // key * 265
+                key = (key.wrapping_add(key << 3)).wrapping_add(key << 8) & mask; // key * 265
                 key = key ^ (key >> 14);
-                key = (key.wrapping_add(key << 2)).wrapping_add(key << 4); // key * 21
+                key = (key.wrapping_add(key << 2)).wrapping_add(key << 4) & mask; // key * 21
                 key = key ^ (key >> 28);
-                key = key.wrapping_add(key << 31);
+                key = key.wrapping_add(key << 31) & mask;
                 key
             },
-            HashMode::Fast => xxh3_64(&item.to_le_bytes()),
+            HashMode::Fast => xxh3_64_with_seed(&item.to_le_bytes(), self.seed) & mask,
+            // the C CQF only has a 32-bit seed
+            HashMode::Murmur => murmur64a(&item.to_le_bytes(), self.seed as u32) & mask,
         }
     }
 
     pub fn invert_hash(&self, item: u64) -> Option<u64> {
+        let mask = bitmask(self.hash_bits());
         match self.hash_mode {
             HashMode::None => Some(item),
             HashMode::Invertible => {
@@ -424,60 +1075,99 @@ impl CQF {
 
                 // Invert key = key + (key << 31)
                 tmp = key.wrapping_sub(key<<31);
-                key = key.wrapping_sub(tmp<<31);
+                key = key.wrapping_sub(tmp<<31) & mask;
 
                 // Invert key = key ^ (key >> 28)
                 tmp = key^key>>28;
-                key = key^tmp>>28;
+                key ^= tmp>>28;
 
                 // Invert key *= 21
-                key = key.wrapping_mul(14933078535860113213);
+                key = key.wrapping_mul(14933078535860113213) & mask;
 
                 // Invert key = key ^ (key >> 14)
                 tmp = key^key>>14;
                 tmp = key^tmp>>14;
                 tmp = key^tmp>>14;
-                key = key^tmp>>14;
+                key ^= tmp>>14;
 
                 // Invert key *= 265
-                key = key.wrapping_mul(15244667743933553977);
+                key = key.wrapping_mul(15244667743933553977) & mask;
 
                 // Invert key = key ^ (key >> 24)
                 tmp = key^key>>24;
-                key = key^tmp>>24;
+                key ^= tmp>>24;
 
                 // Invert key = (~key) + (key << 21)
                 tmp = !key;
                 tmp = !(key.wrapping_sub(tmp<<21));
                 tmp = !(key.wrapping_sub(tmp<<21));
-                key = !(key.wrapping_sub(tmp<<21));
+                key = !(key.wrapping_sub(tmp<<21)) & mask;
 
                 Some(key)
             },
-            HashMode::Fast => None,
+            HashMode::Fast | HashMode::Murmur => None,
         }
     }
 
-    fn calc_qr(&self, hash: u64) -> (usize, u64) {
+    pub(crate) fn hash_bits(&self) -> u64 {
+        self.quotient_bits + self.remainder_bits
+    }
+
+    pub(crate) fn slot_bits(&self) -> u64 {
+        self.remainder_bits + self.value_bits
+    }
+
+    pub(crate) fn calc_qr(&self, hash: u64) -> (usize, u64) {
         let quotient = (hash >> self.remainder_bits) & ((1 << self.quotient_bits) - 1);
         let remainder = hash & ((1 << self.remainder_bits) - 1);
         (quotient as usize, remainder)
     }
 
+    // iterates from the first item whose hash is at least hash
+    pub fn iter_from(&self, hash: u64) -> CQFIterator<'_> {
+        let mut iter = self.into_iter();
+        iter.seek(hash);
+        iter
+    }
+
+    // iterates from the first run at or after quotient
+    pub(crate) fn iter_from_quotient(&self, quotient: usize) -> CQFIterator<'_> {
+        let mut block_idx = quotient / 64;
+        let mut occupieds = self.get_block(block_idx).occupieds & !bitmask(quotient as u64 % 64);
+        while occupieds == 0 && block_idx + 1 < self.nblocks as usize {
+            block_idx += 1;
+            occupieds = self.get_block(block_idx).occupieds;
+        }
+        let run = if occupieds == 0 { self.xnslots as usize } else { 64 * block_idx + occupieds.tzcnt() as usize };
+        CQFIterator {
+            qf: self,
+            position: if run == 0 || run >= self.xnslots as usize { run } else { self.run_end(run - 1) + 1 },
+            run,
+            first: true
+        }
+    }
+
     pub fn build_hash(&self, quotient: usize, remainder: u64) -> u64 {
         ((quotient as u64) << self.remainder_bits) | remainder
     }
 
+    fn item_at(&self, quotient: usize, index: usize) -> FilterItem {
+        let (mut current_slot, mut current_count): (u64, u64) = (0, 0);
+        self.decode_counter(index, &mut current_slot, &mut current_count);
+        let hash = self.build_hash(quotient, current_slot >> self.value_bits);
+        FilterItem { hash, item: self.invert_hash(hash), count: current_count, value: current_slot & bitmask(self.value_bits) }
+    }
+
     fn is_occupied(&self, index: usize) -> bool {
         let block_idx = index / 64;
         let slot = index % 64;
         self.get_block(block_idx).is_occupied(slot)
     }
 
-    fn set_occupied(&mut self, index: usize, val: bool) {
+    fn set_occupied(&self, index: usize, val: bool) {
         let block_idx = index / 64;
         let slot = index % 64;
-        self.get_block_mut(block_idx).set_occupied(slot, val)
+        self.update_block(block_idx, |block| block.set_occupied(slot, val))
     }
 
     fn is_runend(&self, index: usize) -> bool {
@@ -486,34 +1176,46 @@ impl CQF {
         self.get_block(block_idx).is_runend(slot)
     }
 
-    fn set_runend(&mut self, index: usize, val: bool) {
+    fn set_runend(&self, index: usize, val: bool) {
         let block_idx = index / 64;
         let slot = index % 64;
-        self.get_block_mut(block_idx).set_runend(slot, val)
+        self.update_block(block_idx, |block| block.set_runend(slot, val))
     }
 
-    fn is_count(&self, index: usize) -> bool {
-        let block_idx = index / 64;
-        let slot = index % 64;
-        self.get_block(block_idx).is_count(slot)
+    fn get_slot(&self, index: usize) -> u64 {
+        let bits = self.slot_bits() as usize;
+        self.read_bits(index * bits, bits)
     }
 
-    fn set_count(&mut self, index: usize, val: bool) {
-        let block_idx = index / 64;
-        let slot = index % 64;
-        self.get_block_mut(block_idx).set_count(slot, val)
+    fn set_slot(&self, index: usize, val: u64) {
+        let bits = self.slot_bits() as usize;
+        self.write_bits(index * bits, bits, val)
     }
 
-    fn get_slot(&self, index: usize) -> u64 {
-        let block_idx = index / 64;
-        let slot = index % 64;
-        self.get_block(block_idx).get_slot(slot)
+    // get_bits and set_bits over the slots, a word at a time so nothing outside the words being
+    // read or written is touched
+    fn read_bits(&self, pos: usize, nbits: usize) -> u64 {
+        let word = pos / 64;
+        let shift = pos % 64;
+        let mut val = self.slots.get(word) >> shift;
+        if shift + nbits > 64 {
+            val |= self.slots.get(word + 1) << (64 - shift);
+        }
+        val & bitmask(nbits as u64)
     }
 
-    fn set_slot(&mut self, index: usize, val: u64) {
-        let block_idx = index / 64;
-        let slot = index % 64;
-        self.get_block_mut(block_idx).set_slot(slot, val)
+    fn write_bits(&self, pos: usize, nbits: usize, val: u64) {
+        let word = pos / 64;
+        let shift = pos % 64;
+        let mask = bitmask(nbits as u64);
+        let val = val & mask;
+        // SAFETY: see update_block
+        unsafe { self.slots.set(word, (self.slots.get(word) & !(mask << shift)) | (val << shift)) };
+        if shift + nbits > 64 {
+            let written = 64 - shift;
+            // SAFETY: see update_block
+            unsafe { self.slots.set(word + 1, (self.slots.get(word + 1) & !(mask >> written)) | (val >> written)) };
+        }
     }
 
     fn might_be_empty(&self, index: usize) -> bool {
@@ -522,25 +1224,28 @@ impl CQF {
         !self.get_block(block_idx).is_occupied(slot) && !self.get_block(block_idx).is_runend(slot)
     }
 
-    fn get_block(&self, block_idx: usize) -> &Block {
-        match self.blocks.get(block_idx) {
-            Some(block) => block,
-            None => panic!("Tried getting block at idx {}, we only have {} blocks", block_idx, self.blocks.len())
-        }
+    // blocks are read and written by value, so nothing ever borrows more of the table than the
+    // block it's looking at
+    fn get_block(&self, block_idx: usize) -> Block {
+        self.blocks.get(block_idx)
     }
 
-    fn get_block_mut(&mut self, block_idx: usize) -> &mut Block {
-        let nblocks = self.blocks.len();
-        match self.blocks.get_mut(block_idx) {
-            Some(block) => block,
-            None => panic!("Tried getting block at idx {}, we only have {} blocks", block_idx, nblocks)
-        }
+    // the table is written through &self so a ConcurrentCQF's threads can each change the
+    // regions they hold without any of them borrowing the rest.
+    // SAFETY: every write comes from a method with &mut self, or from insert_within or
+    // remove_in_place, whose callers hold the region locks over every block and slot word those
+    // can touch. Regions are 4096 slots, a whole number of blocks and slot words, so no two
+    // threads ever write, or read what the other writes, the same block or word at once
+    fn update_block<F: FnOnce(&mut Block)>(&self, block_idx: usize, update: F) {
+        let mut block = self.get_block(block_idx);
+        update(&mut block);
+        unsafe { self.blocks.set(block_idx, block) };
     }
 
     fn run_end(&self, quotient: usize) -> usize {
         let block_idx: usize = quotient / 64;
         let intrablock_offset: usize = quotient % 64;
-        let blocks_offset: usize = self.get_block(block_idx).offset.into();
+        let blocks_offset = self.get_block(block_idx).offset as usize;
         let intrablock_rank: usize = bitrank(self.get_block(block_idx).occupieds, intrablock_offset);
 
         if intrablock_rank == 0 {
@@ -583,7 +1288,9 @@ impl CQF {
 pub struct FilterItem {
     pub hash: u64,
     pub item: Option<u64>,
-    pub count: u64
+    pub count: u64,
+    // always 0 unless the filter was built with value bits
+    pub value: u64
 }
 
 pub struct CQFIterator<'a> {
@@ -598,32 +1305,31 @@ impl<'a> IntoIterator for &'a CQF {
     type IntoIter = CQFIterator<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let mut position = 0;
-        if !self.is_occupied(0) {
-            let mut block_index: usize = 0;
-            let mut idx = bitselect(self.get_block(0).occupieds, 0);
-            if idx == 64 {
-                while idx == 64 && block_index < (self.nblocks - 1) as usize {
-                    block_index += 1;
-                    idx = bitselect(self.get_block(block_index).occupieds, 0);
-                }
-            }
-            position = block_index * 64 + idx;
-        }
-
-        CQFIterator {
-            qf: self,
-            position: if position == 0 { 0 } else { self.run_end(position - 1) + 1 },
-            run: position as usize,
-            first: true
-        }
+        self.iter_from_quotient(0)
     }
 }
 
 impl<'a> CQFIterator<'a> {
+    // moves to the first item whose hash is at least hash, forwards or backwards. The run the
+    // hash's quotient starts in is found the same way an insert finds it, and only that run's
+    // smaller items are stepped over
+    pub fn seek(&mut self, hash: u64) {
+        if hash > bitmask(self.qf.hash_bits()) {
+            self.run = self.qf.xnslots as usize;
+            self.first = true;
+            return;
+        }
+        *self = self.qf.iter_from_quotient(self.qf.calc_qr(hash).0);
+        while self.run < self.qf.xnslots as usize && self.qf.item_at(self.run, self.position).hash < hash {
+            if !self.move_position() {
+                self.run = self.qf.xnslots as usize;
+            }
+        }
+    }
+
     fn move_position(&mut self) -> bool {
         if self.position >= self.qf.xnslots as usize {
-            return false;
+            false
         } else {
             let (mut current_remainder, mut current_count): (u64, u64) = (0, 0);
             self.position = self.qf.decode_counter(self.position, &mut current_remainder, &mut current_count);
@@ -632,7 +1338,7 @@ impl<'a> CQFIterator<'a> {
                 if self.position >= self.qf.xnslots as usize {
                     return false;
                 }
-                return true;
+                true
             } else {
                 let mut block_idx = self.run / 64;
                 let mut rank = bitrank(self.qf.get_block(block_idx).occupieds, self.run % 64);
@@ -662,7 +1368,7 @@ impl<'a> CQFIterator<'a> {
                     return false;
                 }
 
-                return true;
+                true
             }
         }
     }
@@ -674,38 +1380,35 @@ impl<'a> Iterator for CQFIterator<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         if self.first {
             self.first = false;
-            let (mut current_remainder, mut current_count): (u64, u64) = (0, 0);
-            self.qf.decode_counter(self.position, &mut current_remainder, &mut current_count);
-            let hash = self.qf.build_hash(self.run, current_remainder);
-            return Some(FilterItem { hash, item: self.qf.invert_hash(hash), count: current_count });
+            if self.run >= self.qf.xnslots as usize {
+                return None;
+            }
+            return Some(self.qf.item_at(self.run, self.position));
         }
         let can_move = self.move_position();
         if !can_move {
             return None;
         }
-        let (mut current_remainder, mut current_count): (u64, u64) = (0, 0);
-        self.qf.decode_counter(self.position, &mut current_remainder, &mut current_count);
-        let hash = self.qf.build_hash(self.run, current_remainder);
-        Some(FilterItem { hash, item: self.qf.invert_hash(hash), count: current_count })
+        Some(self.qf.item_at(self.run, self.position))
     }
 }
 
 impl Block {
     fn offset_lower_bound(&self, slot: u64) -> u64 {
         let occupieds = self.occupieds & bitmask(slot+1);
-        let offset_64: u64 = self.offset.into();
+        let offset_64 = self.offset;
         if offset_64 <= slot {
             let runends = (self.runends & bitmask(slot)) >> offset_64;
             return occupieds.popcnt() - runends.popcnt();
         }
-        return offset_64 - slot + occupieds.popcnt();
+        offset_64 - slot + occupieds.popcnt()
     }
 
-    fn is_occupied(&self, slot: usize) -> bool {
+    pub(crate) fn is_occupied(&self, slot: usize) -> bool {
         ((self.occupieds >> slot) & 1) != 0
     }
 
-    fn set_occupied(&mut self, slot: usize, bit: bool) {
+    pub(crate) fn set_occupied(&mut self, slot: usize, bit: bool) {
         if bit {
             self.occupieds |= 1 << slot;
         } else {
@@ -713,56 +1416,39 @@ impl Block {
         }
     }
 
-    fn is_runend(&self, slot: usize) -> bool {
+    pub(crate) fn is_runend(&self, slot: usize) -> bool {
         ((self.runends >> slot) & 1) != 0
     }
 
-    fn set_runend(&mut self, slot: usize, bit: bool) {
+    pub(crate) fn set_runend(&mut self, slot: usize, bit: bool) {
         if bit {
             self.runends |= 1 << slot;
         } else {
             self.runends &= !(1 << slot);
         }
     }
-    
-    fn is_count(&self, slot: usize) -> bool {
-        ((self.counts >> slot) & 1) != 0
-    }
-
-    fn set_count(&mut self, slot: usize, bit: bool) {
-        if bit {
-            self.counts |= 1 << slot;
-        } else {
-            self.counts &= !(1 << slot);
-        }
-    }
-
-    fn set_slot(&mut self, slot: usize, value: u64) {
-        self.remainders[slot] = value;
-    }
-
-    fn get_slot(&self, slot: usize) -> u64 {
-        self.remainders[slot]
-    }
 }
 
-fn bitrank(val: u64, pos: usize) -> usize {
+pub(crate) fn bitrank(val: u64, pos: usize) -> usize {
     if pos == 63 {
-        (val & u64::MAX).popcnt() as usize
+        val.popcnt() as usize
     } else {
         (val & ((2 << pos) - 1)).popcnt() as usize
     }
 }
 
 fn popcntv(val: u64, ignore: usize) -> usize {
-    if ignore % 64 != 0 {
+    if !ignore.is_multiple_of(64) {
         (val & !(bitmask(ignore as u64 % 64))).popcnt() as usize
     } else {
         val.popcnt() as usize
     }
 }
 
-fn bitselect(val: u64, rank: usize) -> usize {
+pub(crate) fn bitselect(val: u64, rank: usize) -> usize {
+    if rank >= 64 {
+        return 64;
+    }
     (1 << rank as u64).pdep(val).tzcnt() as usize
 }
 
@@ -770,6 +1456,71 @@ fn bitselectv(val: u64, ignore: usize, rank: usize) -> usize {
     bitselect(val & !(bitmask(ignore as u64 % 64)), rank)
 }
 
-fn bitmask(nbits: u64) -> u64 {
+pub(crate) fn get_bits(words: &[u64], pos: usize, nbits: usize) -> u64 {
+    let word = pos / 64;
+    let shift = pos % 64;
+    let mut val = words[word] >> shift;
+    if shift + nbits > 64 {
+        val |= words[word + 1] << (64 - shift);
+    }
+    val & bitmask(nbits as u64)
+}
+
+pub(crate) fn set_bits(words: &mut [u64], pos: usize, nbits: usize, val: u64) {
+    let word = pos / 64;
+    let shift = pos % 64;
+    let mask = bitmask(nbits as u64);
+    let val = val & mask;
+    words[word] = (words[word] & !(mask << shift)) | (val << shift);
+    if shift + nbits > 64 {
+        let written = 64 - shift;
+        words[word + 1] = (words[word + 1] & !(mask >> written)) | (val >> written);
+    }
+}
+
+pub(crate) fn bitmask(nbits: u64) -> u64 {
     if nbits == 64 { u64::MAX } else { (1 << nbits) - 1 }
-}
\ No newline at end of file
+}
+
+// a straight port of the C CQF's MurmurHash64A
+fn murmur64a(key: &[u8], seed: u32) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed as u64 ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &byte) in tail.iter().enumerate() {
+            h ^= (byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+// the slots a table of 2^lognslots has, counting the overflow past the last quotient
+fn xnslots_for(lognslots: u64) -> u64 {
+    let nslots = (1u64 << lognslots) as f32;
+    (nslots + 10.0 * nslots.sqrt()) as u64
+}
+
+fn try_alloc<T: Clone>(len: u64, value: T) -> Result<Vec<T>, CqfError> {
+    let len = usize::try_from(len).map_err(|_| CqfError::AllocationFailure)?;
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(len).map_err(|_| CqfError::AllocationFailure)?;
+    vec.resize(len, value);
+    Ok(vec)
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum CqfError {
+    // parameters that can't describe a working filter, saying which constraint they broke
+    InvalidParameters(&'static str),
+    // the filters hash items differently (mode or seed) so they can't be combined
+    HashModeMismatch,
+    // the filters hash to different widths, which only merge_truncated can bring together
+    HashWidthMismatch,
+    AllocationFailure,
+    // the filter is out of room and can't grow any further
+    CapacityExceeded,
+    CountOverflow,
+    // a key was given a different value than the one it has, under ValueMerge::Reject
+    ValueCollision,
+    Io(std::io::Error),
+    // the file doesn't start with the CQF magic, so it isn't one of ours
+    BadMagic,
+    UnsupportedVersion(u64),
+    // the file ended before all of its sections were read
+    Truncated,
+    // a header field that can't describe a real filter, naming the field
+    InvalidHeader(&'static str),
+    // the stored checksum didn't match, naming the section
+    ChecksumMismatch(&'static str),
+    UnsupportedEndianness,
+    // the filter uses something the other format has no way to represent
+    IncompatibleFormat(&'static str),
+}
+
+impl fmt::Display for CqfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CqfError::InvalidParameters(reason) => write!(f, "invalid CQF parameters: {}", reason),
+            CqfError::HashModeMismatch => write!(f, "CQFs must have the same hash mode and seed"),
+            CqfError::HashWidthMismatch => write!(f, "CQFs must have the same hash width"),
+            CqfError::AllocationFailure => write!(f, "couldn't allocate the CQF's table"),
+            CqfError::CapacityExceeded => write!(f, "CQF is full and can't be resized any further"),
+            CqfError::CountOverflow => write!(f, "count overflowed a u64"),
+            CqfError::ValueCollision => write!(f, "key already has a different value"),
+            CqfError::Io(err) => write!(f, "I/O error: {}", err),
+            CqfError::BadMagic => write!(f, "not a CQF file (bad magic)"),
+            CqfError::UnsupportedVersion(version) => write!(f, "unsupported CQF format version {}", version),
+            CqfError::Truncated => write!(f, "CQF file is truncated"),
+            CqfError::InvalidHeader(field) => write!(f, "CQF header has an invalid {}", field),
+            CqfError::ChecksumMismatch(section) => write!(f, "checksum mismatch in the CQF {} section", section),
+            CqfError::UnsupportedEndianness => write!(f, "CQF files can only be used on little-endian hosts"),
+            CqfError::IncompatibleFormat(what) => write!(f, "the C CQF format doesn't support {}", what),
+        }
+    }
+}
+
+impl std::error::Error for CqfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CqfError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CqfError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => CqfError::Truncated,
+            _ => CqfError::Io(err),
+        }
+    }
+}
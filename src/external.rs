@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cqf::bitmask;
+use crate::stream::{self, MERGE_FAN_IN, READER_MEMORY};
+use crate::{CQF, CQFOptions, CqfError, CqfKey, FilterItem};
+
+// tells apart the runs of builders sharing a temp directory in one process
+static NEXT_BUILDER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+struct Entry {
+    hash: u64,
+    value: u64,
+    count: u64,
+}
+
+// builds a filter too big to grow in memory straight into a file. Hashes are buffered up to the
+// memory budget, then sorted and written out as a filter file of their own, and at the end those
+// runs are merged the way merge_files merges, so memory use stays within the budget however many
+// keys go in
+pub struct ExternalBuilder {
+    qf: CQF,
+    temp_dir: PathBuf,
+    id: usize,
+    buffer: Vec<Entry>,
+    capacity: usize,
+    fan_in: usize,
+    runs: Vec<PathBuf>,
+}
+
+impl ExternalBuilder {
+    // hashes keys the way a filter built with options would (mode, width, seed and value bits), and
+    // merges values with its ValueMerge, with a key's earlier values coming first. The table is
+    // never built, the output is sized for what went in
+    pub fn new(options: CQFOptions, temp_dir: PathBuf, memory_budget: usize) -> Result<Self, CqfError> {
+        // the merge needs at least two readers and the writer, each about as big as a reader
+        let fan_in = (memory_budget / READER_MEMORY).saturating_sub(1).min(MERGE_FAN_IN);
+        if fan_in < 2 {
+            return Err(CqfError::InvalidParameters("memory budget is too small to merge runs"));
+        }
+        // sorting a run stably takes half as much again
+        let capacity = memory_budget / std::mem::size_of::<Entry>() * 2 / 3;
+        Ok(Self {
+            qf: options.layout()?,
+            temp_dir,
+            id: NEXT_BUILDER.fetch_add(1, Ordering::Relaxed),
+            buffer: Vec::new(),
+            capacity,
+            fan_in,
+            runs: Vec::new(),
+        })
+    }
+
+    pub fn insert(&mut self, item: u64, count: u64) -> Result<(), CqfError> {
+        self.insert_kv_by_hash(self.qf.calc_hash(item), 0, count)
+    }
+
+    pub fn insert_key<K: CqfKey + ?Sized>(&mut self, key: &K, count: u64) -> Result<(), CqfError> {
+        self.insert_kv_by_hash(self.qf.hash_key(key), 0, count)
+    }
+
+    pub fn insert_by_hash(&mut self, hash: u64, count: u64) -> Result<(), CqfError> {
+        self.insert_kv_by_hash(hash, 0, count)
+    }
+
+    pub fn insert_kv(&mut self, item: u64, value: u64, count: u64) -> Result<(), CqfError> {
+        self.insert_kv_by_hash(self.qf.calc_hash(item), value, count)
+    }
+
+    pub fn insert_kv_by_hash(&mut self, hash: u64, value: u64, count: u64) -> Result<(), CqfError> {
+        if value > bitmask(self.qf.value_bits) {
+            return Err(CqfError::InvalidParameters("value doesn't fit in the filter's value bits"));
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        if self.buffer.len() >= self.capacity {
+            self.spill()?;
+        }
+        // allocated in one go, since growing it by doubling could go past the budget
+        if self.buffer.capacity() == 0 {
+            self.buffer.try_reserve_exact(self.capacity).map_err(|_| CqfError::AllocationFailure)?;
+        }
+        self.buffer.push(Entry { hash: hash & bitmask(self.qf.hash_bits()), value, count });
+        Ok(())
+    }
+
+    // sorts the buffer into a filter file of its own
+    fn spill(&mut self) -> Result<(), CqfError> {
+        // stable, so a key's values stay in the order they went in
+        self.buffer.sort_by_key(|entry| entry.hash);
+        let path = self.temp_dir.join(format!("cqf-run-{}-{}-{}", std::process::id(), self.id, self.runs.len()));
+        self.runs.push(path.clone());
+        let buffer = &self.buffer;
+        self.qf.write_sorted(buffer.len() as u64, &path, || {
+            Ok(buffer.iter().map(|entry| Ok(FilterItem { hash: entry.hash, item: None, count: entry.count, value: entry.value })))
+        })?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    // writes everything inserted to output as a filter file, which CQF::deserialize or CQFView::open
+    // can then load. The runs are removed whether or not it succeeds
+    pub fn finish(mut self, output: PathBuf) -> Result<(), CqfError> {
+        if !self.buffer.is_empty() || self.runs.is_empty() {
+            self.spill()?;
+        }
+        // the buffer isn't needed again, so its memory goes to the merge
+        self.buffer = Vec::new();
+        CQF::merge_files_in_groups(&self.runs, &output, self.fan_in)
+    }
+}
+
+impl Drop for ExternalBuilder {
+    fn drop(&mut self) {
+        stream::remove_all(&self.runs);
+    }
+}
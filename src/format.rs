@@ -0,0 +1,350 @@
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+use crate::{Block, CQF, CqfError, HashMode, Storage};
+
+// on disk a filter is a header of little-endian u64s followed by the blocks and then the packed
+// slots, each section starting on a 64 byte boundary so a mapped file can be read in place.
+// Every section has an xxh3 checksum, the header's covering everything in it before the checksum.
+// Any change to the layout gets a new version, which older readers turn away
+const MAGIC: [u8; 8] = *b"CQFRUST\0";
+const VERSION: u64 = 1;
+const ALIGN: usize = 64;
+// the magic, then the version and the rest of the fields
+const HEADER_LEN: usize = 8 + 16 * 8;
+
+type Result<T> = std::result::Result<T, CqfError>;
+
+struct Header {
+    lognslots: u64,
+    nslots: u64,
+    xnslots: u64,
+    nblocks: u64,
+    noccupied_slots: u64,
+    quotient_bits: u64,
+    remainder_bits: u64,
+    value_bits: u64,
+    hash_mode: HashMode,
+    seed: u64,
+    blocks_offset: u64,
+    slots_offset: u64,
+    blocks_checksum: u64,
+    slots_checksum: u64,
+}
+
+impl Header {
+    fn of(qf: &CQF) -> Self {
+        Self::with_checksums(qf, xxh3_64(as_bytes(&qf.blocks[..])), xxh3_64(as_bytes(&qf.slots[..])))
+    }
+
+    // for a filter whose sections were written out separately and checksummed on the way
+    fn with_checksums(qf: &CQF, blocks_checksum: u64, slots_checksum: u64) -> Self {
+        let (blocks_offset, slots_offset) = section_offsets(qf);
+        Self {
+            lognslots: qf.lognslots,
+            nslots: qf.nslots,
+            xnslots: qf.xnslots,
+            nblocks: qf.nblocks,
+            noccupied_slots: qf.noccupied_slots,
+            quotient_bits: qf.quotient_bits,
+            remainder_bits: qf.remainder_bits,
+            value_bits: qf.value_bits,
+            hash_mode: qf.hash_mode,
+            seed: qf.seed,
+            blocks_offset: blocks_offset as u64,
+            slots_offset: slots_offset as u64,
+            blocks_checksum,
+            slots_checksum,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        for field in [
+            VERSION,
+            self.lognslots,
+            self.nslots,
+            self.xnslots,
+            self.nblocks,
+            self.noccupied_slots,
+            self.quotient_bits,
+            self.remainder_bits,
+            self.value_bits,
+            hash_mode_to_u64(self.hash_mode),
+            self.seed,
+            self.blocks_offset,
+            self.slots_offset,
+            self.blocks_checksum,
+            self.slots_checksum,
+        ] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(&xxh3_64(&bytes).to_le_bytes());
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        // the magic and version come first so a foreign or newer file gets the right error
+        if !bytes.starts_with(&MAGIC[..bytes.len().min(8)]) {
+            return Err(CqfError::BadMagic);
+        }
+        if bytes.len() < 16 {
+            return Err(CqfError::Truncated);
+        }
+        let field = |i: usize| u64::from_le_bytes(bytes[8 + 8 * i..16 + 8 * i].try_into().unwrap());
+        check_version(field(0))?;
+        if bytes.len() < HEADER_LEN {
+            return Err(CqfError::Truncated);
+        }
+        if xxh3_64(&bytes[..HEADER_LEN - 8]) != field(15) {
+            return Err(CqfError::ChecksumMismatch("header"));
+        }
+        let header = Self {
+            lognslots: field(1),
+            nslots: field(2),
+            xnslots: field(3),
+            nblocks: field(4),
+            noccupied_slots: field(5),
+            quotient_bits: field(6),
+            remainder_bits: field(7),
+            value_bits: field(8),
+            hash_mode: hash_mode_from_u64(field(9))?,
+            seed: field(10),
+            blocks_offset: field(11),
+            slots_offset: field(12),
+            blocks_checksum: field(13),
+            slots_checksum: field(14),
+        };
+        header.validate()?;
+        Ok(header)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.lognslots >= 64 || self.nslots != 1 << self.lognslots {
+            return Err(CqfError::InvalidHeader("nslots"));
+        }
+        if self.xnslots < self.nslots || self.nblocks != self.xnslots.div_ceil(64) {
+            return Err(CqfError::InvalidHeader("xnslots"));
+        }
+        if self.remainder_bits < 2 || self.remainder_bits >= 64 || self.quotient_bits > self.lognslots || self.quotient_bits + self.remainder_bits > 64 {
+            return Err(CqfError::InvalidHeader("quotient/remainder bits"));
+        }
+        if self.remainder_bits + self.value_bits > 64 {
+            return Err(CqfError::InvalidHeader("value bits"));
+        }
+        if self.noccupied_slots > self.xnslots {
+            return Err(CqfError::InvalidHeader("occupied slot count"));
+        }
+        if !(self.blocks_offset as usize).is_multiple_of(ALIGN) || (self.blocks_offset as usize) < HEADER_LEN {
+            return Err(CqfError::InvalidHeader("blocks offset"));
+        }
+        let blocks_end = (self.nblocks as usize).checked_mul(std::mem::size_of::<Block>()).and_then(|len| len.checked_add(self.blocks_offset as usize));
+        if !(self.slots_offset as usize).is_multiple_of(ALIGN) || blocks_end.is_none_or(|end| (self.slots_offset as usize) < end) {
+            return Err(CqfError::InvalidHeader("slots offset"));
+        }
+        Ok(())
+    }
+
+    fn nslot_words(&self) -> usize {
+        (self.nblocks * (self.remainder_bits + self.value_bits)) as usize
+    }
+
+    fn into_cqf(self, blocks: Storage<Block>, slots: Storage<u64>) -> CQF {
+        CQF {
+            lognslots: self.lognslots,
+            nslots: self.nslots,
+            xnslots: self.xnslots,
+            nblocks: self.nblocks,
+            noccupied_slots: self.noccupied_slots,
+            quotient_bits: self.quotient_bits,
+            remainder_bits: self.remainder_bits,
+            value_bits: self.value_bits,
+            hash_mode: self.hash_mode,
+            seed: self.seed,
+            blocks,
+            slots,
+            ..Default::default()
+        }
+    }
+}
+
+// worked out from nblocks so it holds for a filter that's being written out without its table
+pub(crate) fn section_offsets(qf: &CQF) -> (usize, usize) {
+    let blocks_offset = HEADER_LEN.next_multiple_of(ALIGN);
+    let slots_offset = (blocks_offset + qf.nblocks as usize * std::mem::size_of::<Block>()).next_multiple_of(ALIGN);
+    (blocks_offset, slots_offset)
+}
+
+pub(crate) fn header_bytes(qf: &CQF, blocks_checksum: u64, slots_checksum: u64) -> Vec<u8> {
+    Header::with_checksums(qf, blocks_checksum, slots_checksum).to_bytes()
+}
+
+// where the sections of a file start and what they should checksum to
+pub(crate) struct Sections {
+    pub(crate) blocks_offset: u64,
+    pub(crate) slots_offset: u64,
+    pub(crate) blocks_checksum: u64,
+    pub(crate) slots_checksum: u64,
+}
+
+// reads only the header, giving back the filter it describes without its table
+pub(crate) fn read_layout<R: Read>(reader: &mut R) -> Result<(CQF, Sections)> {
+    check_endianness()?;
+    let header = read_header(reader)?;
+    let sections = Sections {
+        blocks_offset: header.blocks_offset,
+        slots_offset: header.slots_offset,
+        blocks_checksum: header.blocks_checksum,
+        slots_checksum: header.slots_checksum,
+    };
+    Ok((header.into_cqf(Storage::default(), Storage::default()), sections))
+}
+
+pub(crate) fn serialized_size(qf: &CQF) -> usize {
+    section_offsets(qf).1 + std::mem::size_of_val(&qf.slots[..])
+}
+
+fn check_version(version: u64) -> Result<()> {
+    if version != VERSION {
+        return Err(CqfError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+fn hash_mode_to_u64(mode: HashMode) -> u64 {
+    match mode {
+        HashMode::None => 0,
+        HashMode::Invertible => 1,
+        HashMode::Fast => 2,
+        HashMode::Murmur => 3,
+    }
+}
+
+fn hash_mode_from_u64(mode: u64) -> Result<HashMode> {
+    Ok(match mode {
+        0 => HashMode::None,
+        1 => HashMode::Invertible,
+        2 => HashMode::Fast,
+        3 => HashMode::Murmur,
+        _ => return Err(CqfError::InvalidHeader("hash mode")),
+    })
+}
+
+pub(crate) fn check_endianness() -> Result<()> {
+    // sections are written and mapped as-is, which is only little-endian on little-endian hosts
+    if cfg!(target_endian = "big") {
+        return Err(CqfError::UnsupportedEndianness);
+    }
+    Ok(())
+}
+
+// only for plain-old-data T (Block and u64), which every bit pattern is valid for. Mapped
+// sections are read as these in place the same way
+pub(crate) fn as_bytes<T: Copy>(items: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(items.as_ptr() as *const u8, std::mem::size_of_val(items)) }
+}
+
+pub(crate) fn as_bytes_mut<T: Copy>(items: &mut [T]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(items.as_mut_ptr() as *mut u8, std::mem::size_of_val(items)) }
+}
+
+fn write_padding<W: Write>(writer: &mut W, from: usize, to: usize) -> Result<()> {
+    writer.write_all(&vec![0; to - from])?;
+    Ok(())
+}
+
+fn skip<R: Read>(reader: &mut R, n: usize) -> Result<()> {
+    let skipped = std::io::copy(&mut reader.take(n as u64), &mut std::io::sink())?;
+    if skipped != n as u64 {
+        return Err(CqfError::Truncated);
+    }
+    Ok(())
+}
+
+fn read_header<R: Read>(reader: &mut R) -> Result<Header> {
+    let mut bytes = vec![0; 16];
+    // read the magic and version on their own so a short foreign file isn't reported as truncated
+    reader.read_exact(&mut bytes[..8])?;
+    if bytes[..8] != MAGIC {
+        return Err(CqfError::BadMagic);
+    }
+    reader.read_exact(&mut bytes[8..16])?;
+    check_version(u64::from_le_bytes(bytes[8..16].try_into().unwrap()))?;
+    bytes.resize(HEADER_LEN, 0);
+    reader.read_exact(&mut bytes[16..])?;
+    Header::parse(&bytes)
+}
+
+// reads a section straight into memory, checksumming it on the way in. It's grown a chunk at a
+// time as the data turns up, so a header claiming more than the stream holds can't make it
+// allocate more than one chunk past what was really there
+fn read_section<R: Read, T: Copy + Default>(reader: &mut R, len: usize, checksum: u64, name: &'static str) -> Result<Vec<T>> {
+    let chunk_len = ((1 << 20) / std::mem::size_of::<T>()).max(1);
+    let mut items = Vec::new();
+    let mut hasher = Xxh3::new();
+    while items.len() < len {
+        let start = items.len();
+        items.resize(start + chunk_len.min(len - start), T::default());
+        let chunk = as_bytes_mut(&mut items[start..]);
+        reader.read_exact(chunk)?;
+        hasher.update(chunk);
+    }
+    if hasher.digest() != checksum {
+        return Err(CqfError::ChecksumMismatch(name));
+    }
+    Ok(items)
+}
+
+pub(crate) fn write<W: Write>(qf: &CQF, writer: &mut W) -> Result<()> {
+    check_endianness()?;
+    let header = Header::of(qf);
+    writer.write_all(&header.to_bytes())?;
+    write_padding(writer, HEADER_LEN, header.blocks_offset as usize)?;
+    let blocks = as_bytes(&qf.blocks[..]);
+    writer.write_all(blocks)?;
+    write_padding(writer, header.blocks_offset as usize + blocks.len(), header.slots_offset as usize)?;
+    writer.write_all(as_bytes(&qf.slots[..]))?;
+    writer.flush()?;
+    Ok(())
+}
+
+// available is how many bytes the input holds from the start of the filter, when that's known
+pub(crate) fn read<R: Read>(reader: &mut R, available: Option<u64>) -> Result<CQF> {
+    check_endianness()?;
+    let header = read_header(reader)?;
+    let end = (header.nslot_words() as u64).checked_mul(8).and_then(|len| len.checked_add(header.slots_offset));
+    if available.is_some_and(|available| end.is_none_or(|end| end > available)) {
+        return Err(CqfError::Truncated);
+    }
+
+    skip(reader, header.blocks_offset as usize - HEADER_LEN)?;
+    let blocks: Vec<Block> = read_section(reader, header.nblocks as usize, header.blocks_checksum, "blocks")?;
+    skip(reader, header.slots_offset as usize - header.blocks_offset as usize - std::mem::size_of_val(&blocks[..]))?;
+    let slots: Vec<u64> = read_section(reader, header.nslot_words(), header.slots_checksum, "slots")?;
+
+    Ok(header.into_cqf(Storage::owned(blocks), Storage::owned(slots)))
+}
+
+// only the header is checked up front, so opening a huge file doesn't page all of it in
+pub(crate) fn map(map: Arc<Mmap>) -> Result<CQF> {
+    check_endianness()?;
+    let header = Header::parse(&map)?;
+    let blocks = Storage::mapped(map.clone(), header.blocks_offset as usize, header.nblocks as usize)?;
+    let slots = Storage::mapped(map, header.slots_offset as usize, header.nslot_words())?;
+    Ok(header.into_cqf(blocks, slots))
+}
+
+// checks the sections of a mapped filter against the checksums in the header it came from
+pub(crate) fn verify(qf: &CQF, map: &Mmap) -> Result<()> {
+    let header = Header::parse(map)?;
+    if xxh3_64(as_bytes(&qf.blocks[..])) != header.blocks_checksum {
+        return Err(CqfError::ChecksumMismatch("blocks"));
+    }
+    if xxh3_64(as_bytes(&qf.slots[..])) != header.slots_checksum {
+        return Err(CqfError::ChecksumMismatch("slots"));
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::borrow::Cow;
+
+pub trait CqfKey {
+    // keys that fit in a u64 go through the filter's hash as-is, so Invertible and None can give
+    // them back when enumerating
+    fn as_u64(&self) -> Option<u64> {
+        None
+    }
+
+    // everything else is hashed from these bytes
+    fn key_bytes(&self) -> Cow<'_, [u8]>;
+}
+
+// u64 keys hash their 8 bytes bare, so byte keys get their length and a tag byte appended to
+// keep them from ever handing the hash the same input (b"abcdefgh" and its u64 would collide)
+const BYTES_TAG: u8 = 0xb7;
+
+pub(crate) fn hash_input<K: CqfKey + ?Sized>(key: &K) -> Vec<u8> {
+    let bytes = key.key_bytes();
+    let mut input = Vec::with_capacity(bytes.len() + 9);
+    input.extend_from_slice(&bytes);
+    input.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    input.push(BYTES_TAG);
+    input
+}
+
+macro_rules! impl_cqf_key_uint {
+    ($($t:ty),*) => {
+        $(
+            impl CqfKey for $t {
+                fn as_u64(&self) -> Option<u64> {
+                    Some(*self as u64)
+                }
+
+                fn key_bytes(&self) -> Cow<'_, [u8]> {
+                    Cow::Owned((*self as u64).to_le_bytes().to_vec())
+                }
+            }
+        )*
+    };
+}
+
+impl_cqf_key_uint!(u8, u16, u32, u64, usize);
+
+impl CqfKey for u128 {
+    fn as_u64(&self) -> Option<u64> {
+        u64::try_from(*self).ok()
+    }
+
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        match self.as_u64() {
+            Some(small) => Cow::Owned(small.to_le_bytes().to_vec()),
+            None => Cow::Owned(self.to_le_bytes().to_vec()),
+        }
+    }
+}
+
+impl CqfKey for [u8] {
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl<const N: usize> CqfKey for [u8; N] {
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl CqfKey for Vec<u8> {
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl CqfKey for str {
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+}
+
+impl CqfKey for String {
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.as_bytes())
+    }
+}
+
+impl<K: CqfKey + ?Sized> CqfKey for &K {
+    fn as_u64(&self) -> Option<u64> {
+        (**self).as_u64()
+    }
+
+    fn key_bytes(&self) -> Cow<'_, [u8]> {
+        (**self).key_bytes()
+    }
+}
+
+// each field is length-prefixed so ("ab", "c") and ("a", "bc") hash differently
+macro_rules! impl_cqf_key_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: CqfKey),+> CqfKey for ($($name,)+) {
+            fn key_bytes(&self) -> Cow<'_, [u8]> {
+                #[allow(non_snake_case)]
+                let ($($name,)+) = self;
+                let mut bytes = Vec::new();
+                $(
+                    let field = $name.key_bytes();
+                    bytes.extend_from_slice(&(field.len() as u64).to_le_bytes());
+                    bytes.extend_from_slice(&field);
+                )+
+                Cow::Owned(bytes)
+            }
+        }
+    };
+}
+
+impl_cqf_key_tuple!(A, B);
+impl_cqf_key_tuple!(A, B, C);
+impl_cqf_key_tuple!(A, B, C, D);
@@ -1,17 +1,48 @@
 mod cqf;
+mod options;
+mod key;
+mod concurrent;
+mod storage;
+mod format;
+mod cformat;
+mod view;
+mod error;
+mod resize;
+mod merge;
+mod stream;
+mod external;
+mod batch;
+mod ordered;
+mod parallel;
+mod range_filter;
 pub use cqf::*;
+pub use options::*;
+pub use key::*;
+pub use concurrent::*;
+pub use view::*;
+pub use error::*;
+pub use resize::*;
+pub use merge::*;
+pub use stream::*;
+pub use external::*;
+pub use parallel::*;
+pub use range_filter::*;
+use storage::Storage;
 
 #[cfg(test)]
+#[allow(clippy::needless_range_loop)]
 mod tests {
-    use std::{collections::HashSet, path::PathBuf}; 
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
 
     use super::*;
-    use rand::Rng;
+    use rand::{Rng, distributions::Alphanumeric};
+    use itertools::Itertools;
     use anyhow::Result;
 
     #[test]
     fn insert() -> Result<()> {
-        let mut qf = CQF::build(23, 23, HashMode::Fast);
+        let mut qf = CQF::try_build(23, 23, HashMode::Fast)?;
 
         let n_strings: usize = 10_000_000;
         //let mut strings: Vec<String> = Vec::with_capacity(n_strings);
@@ -55,7 +86,7 @@ mod tests {
 
     #[test]
     fn enumerate() -> Result<()> {
-        let mut qf = CQF::build(25, 25, HashMode::Fast);
+        let mut qf = CQF::try_build(25, 25, HashMode::Fast)?;
 
         let n_strings: usize = 10_000_000;
         let count = 3;
@@ -114,8 +145,8 @@ mod tests {
 
     #[test]
     fn merge() -> Result<()> {
-        let mut qf1 = CQF::build(25, 25, HashMode::Fast);
-        let mut qf2 = CQF::build(25, 25, HashMode::Fast);
+        let mut qf1 = CQF::try_build(25, 25, HashMode::Fast)?;
+        let mut qf2 = CQF::try_build(25, 25, HashMode::Fast)?;
 
         let n_strings: usize = 10_000_000;
         let count = 3;
@@ -152,7 +183,7 @@ mod tests {
             items.insert(item);
         }
 
-        let qf3 = CQF::from(qf1, qf2);
+        let qf3 = CQF::try_merge(&[&qf1, &qf2])?;
 
         let mut merge_items = HashSet::with_capacity(n_strings);
         for item in qf3.into_iter() {
@@ -170,7 +201,7 @@ mod tests {
 
     #[test]
     fn serialize() -> Result<()> {
-        let mut qf = CQF::build(23, 23, HashMode::Invertible);
+        let mut qf = CQF::try_build(23, 23, HashMode::Invertible)?;
 
         let n_strings: usize = 10_000_000;
         //let mut strings: Vec<String> = Vec::with_capacity(n_strings);
@@ -210,8 +241,8 @@ mod tests {
         }
         assert_eq!(present, 0);
 
-        qf.serialize(PathBuf::from("/home/ari/Documents/GitHub/cqf-rust/serialize-test.cqf"))?;
-        let read_qf = CQF::deserialize(PathBuf::from("/home/ari/Documents/GitHub/cqf-rust/serialize-test.cqf"))?;
+        let bytes = qf.to_bytes()?;
+        let read_qf = CQF::from_bytes(&bytes)?;
         let mut items = HashSet::with_capacity(n_strings);
         for item in qf.into_iter() {
             items.insert(item);
@@ -228,7 +259,7 @@ mod tests {
 
     #[test]
     fn invert() -> Result<()> {
-        let mut qf = CQF::build(25, 25, HashMode::Invertible);
+        let mut qf = CQF::try_build(25, 25, HashMode::Invertible)?;
 
         let n_vals: usize = 10_000_000;
         let count = 3;
@@ -269,4 +300,1047 @@ mod tests {
         assert!(enumerated_set == number_set, "enumerated items don't match originals!");
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn remove() -> Result<()> {
+        let mut qf = CQF::try_build(20, 20, HashMode::Fast)?;
+
+        let n_vals: usize = 1_000_000;
+        let count = 3;
+        let mut numbers: Vec<u64> = Vec::with_capacity(n_vals);
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..n_vals {
+            numbers.push(rng.gen())
+        }
+
+        for i in 0..n_vals {
+            qf.insert(numbers[i], count)?;
+        }
+        for i in 0..n_vals/2 {
+            assert_eq!(qf.remove(numbers[i], 1)?, 1);
+        }
+        for i in 0..n_vals/4 {
+            assert_eq!(qf.remove(numbers[i], 1)?, 1);
+        }
+        for i in n_vals/2..n_vals {
+            assert_eq!(qf.delete_all(numbers[i])?, count);
+        }
+
+        for i in 0..n_vals/4 {
+            assert_eq!(qf.query(numbers[i]), count - 2, "wrong count after removal!");
+        }
+        for i in n_vals/4..n_vals/2 {
+            assert_eq!(qf.query(numbers[i]), count - 1, "wrong count after removal!");
+        }
+        for i in n_vals/2..n_vals {
+            assert_eq!(qf.query(numbers[i]), 0, "deleted item still present!");
+        }
+        assert_eq!(qf.into_iter().count(), n_vals / 2);
+
+        for i in 0..n_vals/2 {
+            assert!(qf.remove(numbers[i], count)? > 0);
+        }
+        assert_eq!(qf.into_iter().count(), 0);
+        assert_eq!(qf.get_load_factor(), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn packed_remainders() -> Result<()> {
+        let wide = CQF::try_build(20, 20, HashMode::Invertible)?;
+        let mut qf = CQFOptions::new(20).hash_mode(HashMode::Invertible).remainder_bits(8).build()?;
+        assert!(qf.total_size_in_bytes() * 3 < wide.total_size_in_bytes(), "8-bit remainders should take a fraction of the space!");
+
+        // every key below 2^28 gets its own 28-bit hash, so nothing collides
+        let n_vals: u64 = 500_000;
+        let count = 3;
+        for i in 0..n_vals {
+            qf.insert(i, count)?;
+        }
+        for i in 0..n_vals {
+            assert_eq!(qf.query(i), count, "wrong count!");
+        }
+        let mut enumerated_set: HashSet<u64> = HashSet::with_capacity(n_vals as usize);
+        for item in qf.into_iter() {
+            assert_eq!(item.count, count);
+            enumerated_set.insert(item.item.unwrap());
+        }
+        assert!(enumerated_set == (0..n_vals).collect(), "enumerated items don't match originals!");
+
+        // without hashing, keys past the 30-bit width lose their high bits
+        let mut qf = CQFOptions::new(20).hash_mode(HashMode::None).remainder_bits(10).build()?;
+        qf.insert(5, 1)?;
+        qf.insert(5 + (1 << 30), 1)?;
+        assert_eq!(qf.query(5), 2);
+        assert_eq!(qf.into_iter().map(|item| (item.item, item.count)).collect_vec(), [(Some(5), 2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn large_counts() -> Result<()> {
+        // 8-bit remainders with 20-bit hashes, so keys below 2^20 never collide
+        let mut qf1 = CQFOptions::new(12).hash_mode(HashMode::Invertible).remainder_bits(8).build()?;
+        let mut qf2 = CQFOptions::new(12).hash_mode(HashMode::Invertible).remainder_bits(8).build()?;
+
+        let n_vals: u64 = 2_000;
+        let count_of = |i: u64| (i << 40) | (i % 7 + 1);
+        for i in 0..n_vals {
+            qf1.insert(i, count_of(i))?;
+        }
+        for i in 0..n_vals {
+            assert_eq!(qf1.query(i), count_of(i), "wrong count!");
+        }
+        for i in n_vals/2..n_vals*2 {
+            qf2.insert(i, i + 1)?;
+        }
+        for i in 0..n_vals/2 {
+            assert_eq!(qf1.remove(i, i << 40)?, i << 40);
+            assert_eq!(qf1.query(i), i % 7 + 1, "wrong count after removal!");
+        }
+
+        let mut counter = 0;
+        for item in qf1.into_iter() {
+            let i = item.item.unwrap();
+            assert_eq!(item.count, if i < n_vals/2 { i % 7 + 1 } else { count_of(i) });
+            counter += 1;
+        }
+        assert_eq!(counter, n_vals);
+
+        let merged = CQF::try_merge(&[&qf1, &qf2])?;
+        for i in 0..n_vals*2 {
+            let expected = match i {
+                i if i < n_vals/2 => i % 7 + 1,
+                i if i < n_vals => count_of(i) + i + 1,
+                i => i + 1,
+            };
+            assert_eq!(merged.query(i), expected, "wrong count after merge!");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keys() -> Result<()> {
+        let mut qf = CQF::try_build(20, 20, HashMode::Fast)?;
+
+        let n_strings: usize = 200_000;
+        let mut strings: Vec<String> = Vec::with_capacity(n_strings);
+        let mut rng = rand::thread_rng();
+        for _ in 0..n_strings {
+            let s: String = (&mut rng)
+                .sample_iter(&Alphanumeric)
+                .take(12)
+                .map(char::from)
+                .collect();
+            strings.push(s);
+        }
+
+        for i in 0..n_strings/2 {
+            qf.insert_key(strings[i].as_str(), 3)?;
+            qf.insert_key(&(i as u64, strings[i].as_bytes()), 1)?;
+        }
+        for i in 0..n_strings/2 {
+            assert_eq!(qf.query_key(strings[i].as_bytes()), 3, "false negative!");
+            assert_eq!(qf.query_key(&strings[i]), 3, "false negative!");
+            assert_eq!(qf.query_key(&(i as u64, strings[i].as_bytes())), 1, "false negative!");
+        }
+        let mut present: u32 = 0;
+        for i in n_strings/2..n_strings {
+            if qf.query_key(strings[i].as_bytes()) > 0 {
+                present += 1;
+            }
+        }
+        assert_eq!(present, 0);
+        assert_eq!(qf.remove_key(&strings[0], 3)?, 3);
+        assert_eq!(qf.query_key(&strings[0]), 0);
+
+        // byte keys don't hash like the u64 with the same bytes
+        for mode in [HashMode::Fast, HashMode::Murmur] {
+            let qf = CQF::try_build(20, 20, mode)?;
+            assert_ne!(qf.hash_key(b"abcdefgh"), qf.hash_key(&u64::from_le_bytes(*b"abcdefgh")));
+            assert_ne!(qf.hash_key(b""), qf.hash_key(&0u64));
+        }
+
+        // keys that fit in 64 bits still come back out of an invertible filter
+        let mut qf = CQF::try_build(20, 20, HashMode::Invertible)?;
+        let ids: Vec<u128> = (0..1000).map(|i| i * 7919).chain([u128::MAX - 1]).collect();
+        for id in &ids {
+            qf.insert_key(id, 1)?;
+        }
+        for id in &ids {
+            assert_eq!(qf.query_key(id), 1, "false negative!");
+        }
+        let enumerated: HashSet<u64> = qf.into_iter().filter_map(|item| item.item).collect();
+        for id in &ids[..1000] {
+            assert!(enumerated.contains(&(*id as u64)), "small ids should round-trip!");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent() -> Result<()> {
+        // start small so the threads have to resize out from under each other
+        let qf = ConcurrentCQF::build(12, 12, HashMode::Invertible)?;
+        let n_threads: u64 = 8;
+        let n_vals: u64 = 200_000;
+        std::thread::scope(|s| {
+            for t in 0..n_threads {
+                let qf = &qf;
+                s.spawn(move || {
+                    // every thread inserts every value so the same counters get hit from all of them
+                    for i in 0..n_vals {
+                        let val = (i * n_threads + t) % n_vals;
+                        qf.insert(val, val % 3 + 1).unwrap();
+                    }
+                });
+            }
+        });
+        for i in 0..n_vals {
+            assert_eq!(qf.query(i), n_threads * (i % 3 + 1));
+        }
+
+        std::thread::scope(|s| {
+            for t in 0..n_threads {
+                let qf = &qf;
+                s.spawn(move || {
+                    for i in (t..n_vals).step_by(n_threads as usize) {
+                        if i % 2 == 0 {
+                            assert_eq!(qf.delete_all(i).unwrap(), n_threads * (i % 3 + 1));
+                        } else {
+                            assert_eq!(qf.query(i), n_threads * (i % 3 + 1));
+                        }
+                    }
+                });
+            }
+        });
+
+        let qf = qf.into_inner();
+        let mut total = 0;
+        for item in qf.into_iter() {
+            let val = item.item.unwrap();
+            assert_eq!(val % 2, 1);
+            assert_eq!(item.count, n_threads * (val % 3 + 1));
+            total += 1;
+        }
+        assert_eq!(total, n_vals / 2);
+        Ok(())
+    }
+
+    #[test]
+    fn view() -> Result<()> {
+        let mut qf = CQF::try_build(16, 16, HashMode::Invertible)?;
+        let n_vals: u64 = 50_000;
+        for i in 0..n_vals {
+            qf.insert(i * 31, i % 5 + 1)?;
+        }
+
+        let path = std::env::temp_dir().join(format!("cqf-view-test-{}.cqf", std::process::id()));
+        qf.serialize(path.clone())?;
+        let view = CQFView::open(path.clone())?;
+        for i in 0..n_vals {
+            assert_eq!(view.query(i * 31), i % 5 + 1);
+        }
+        assert!(view.iter().eq(qf.into_iter()), "mapped iteration should match the filter it came from!");
+
+        let read_qf = CQF::deserialize(path.clone())?;
+        assert!(read_qf.into_iter().eq(qf.into_iter()));
+
+        // anything without the magic up front gets turned away
+        std::fs::write(&path, b"definitely not a filter")?;
+        assert!(CQFView::open(path.clone()).is_err());
+        assert!(CQF::deserialize(path.clone()).is_err());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn file_format() -> Result<()> {
+        let mut qf = CQFOptions::new(12).hash_mode(HashMode::Fast).seed(0xc0ffee).build()?;
+        for i in 0..3000 {
+            qf.insert(i, 2)?;
+        }
+        let path = std::env::temp_dir().join(format!("cqf-format-test-{}.cqf", std::process::id()));
+        qf.serialize(path.clone())?;
+        let bytes = std::fs::read(&path)?;
+
+        let read_qf = CQF::deserialize(path.clone())?;
+        assert_eq!(read_qf.seed(), 0xc0ffee);
+        for i in 0..3000 {
+            assert_eq!(read_qf.query(i), 2);
+        }
+        CQFView::open(path.clone())?.verify()?;
+
+        // flip a bit in the last slot word
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, &corrupted)?;
+        assert!(matches!(CQF::deserialize(path.clone()), Err(CqfError::ChecksumMismatch("slots"))));
+        assert!(matches!(CQFView::open(path.clone())?.verify(), Err(CqfError::ChecksumMismatch("slots"))));
+
+        let mut corrupted = bytes.clone();
+        corrupted[20] ^= 1;
+        std::fs::write(&path, &corrupted)?;
+        assert!(matches!(CQF::deserialize(path.clone()), Err(CqfError::ChecksumMismatch("header"))));
+
+        let mut newer = bytes.clone();
+        newer[8] = 2;
+        std::fs::write(&path, &newer)?;
+        assert!(matches!(CQF::deserialize(path.clone()), Err(CqfError::UnsupportedVersion(2))));
+
+        std::fs::write(&path, &bytes[..bytes.len() - 100])?;
+        assert!(matches!(CQF::deserialize(path.clone()), Err(CqfError::Truncated)));
+        assert!(matches!(CQFView::open(path.clone()), Err(CqfError::Truncated)));
+
+        // a header claiming a table far bigger than the file is turned away before anything's allocated
+        let huge = CQFOptions::new(40).layout()?;
+        let mut claimed = format::header_bytes(&huge, 0, 0);
+        claimed.resize(format::section_offsets(&huge).0 + 4096, 0);
+        std::fs::write(&path, &claimed)?;
+        assert!(matches!(CQF::deserialize(path.clone()), Err(CqfError::Truncated)));
+        assert!(matches!(CQF::from_bytes(&claimed), Err(CqfError::Truncated)));
+        assert!(matches!(CQF::read_from(&claimed[..]), Err(CqfError::Truncated)));
+
+        std::fs::write(&path, b"CQF")?;
+        assert!(matches!(CQF::deserialize(path.clone()), Err(CqfError::Truncated)));
+        std::fs::write(&path, b"not a filter at all")?;
+        assert!(matches!(CQF::deserialize(path.clone()), Err(CqfError::BadMagic)));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn streams() -> Result<()> {
+        let mut first = CQF::try_build(10, 10, HashMode::Invertible)?;
+        let mut second = CQFOptions::new(11).hash_mode(HashMode::None).remainder_bits(20).build()?;
+        for i in 0..500 {
+            first.insert(i, i + 1)?;
+            second.insert(i << 11, 1)?;
+        }
+
+        // back to back in one stream, each read should stop at the end of its own filter
+        let mut stream = Vec::new();
+        first.write_to(&mut stream)?;
+        second.write_to(&mut stream)?;
+        stream.extend_from_slice(b"trailer");
+        assert_eq!(stream.len(), first.to_bytes()?.len() + second.to_bytes()?.len() + 7);
+
+        let mut reader = stream.as_slice();
+        let read_first = CQF::read_from(&mut reader)?;
+        let read_second = CQF::read_from(&mut reader)?;
+        assert_eq!(reader, b"trailer");
+        assert!(read_first.into_iter().eq(first.into_iter()));
+        assert!(read_second.into_iter().eq(second.into_iter()));
+        Ok(())
+    }
+
+    #[test]
+    fn c_format() -> Result<()> {
+        // hashes of 12345 from the C CQF's MurmurHash64A, with seed 0 and 2038074743
+        for (seed, hash) in [(0, 9847615892405314594), (2038074743, 15828720913771371511)] {
+            let mut qf = CQFOptions::new(10).hash_mode(HashMode::Murmur).seed(seed).build()?;
+            qf.insert(12345, 1)?;
+            assert_eq!(qf.into_iter().next().unwrap().hash, hash);
+        }
+
+        let mut qf = CQFOptions::new(14).hash_mode(HashMode::Invertible).remainder_bits(13).build()?;
+        for i in 0..12_000 {
+            qf.insert(i * 7, i % 9 + 1)?;
+        }
+        for i in 0..50 {
+            qf.insert(i * 7, 1 << 40)?;
+        }
+        let mut bytes = Vec::new();
+        qf.write_c_to(&mut bytes)?;
+        assert_eq!(bytes[..8], 0x0102030405060708u64.to_le_bytes());
+        let field = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize;
+        // nblocks packed blocks, each a u8 offset, two bitmap words and 64 bits_per_slot-bit slots
+        assert_eq!(bytes.len(), 124 + field(92) * (17 + 8 * field(68)));
+
+        let read_qf = CQF::read_c_from(bytes.as_slice())?;
+        assert!(read_qf.into_iter().eq(qf.into_iter()));
+        for i in 0..12_000 {
+            assert_eq!(read_qf.query(i * 7), i % 9 + 1 + if i < 50 { 1 << 40 } else { 0 });
+        }
+
+        // value bits have to account for the rest of the slot, and our own Fast hash has nowhere to go
+        let mut with_values = bytes.clone();
+        with_values[52] = 4;
+        assert!(matches!(CQF::read_c_from(with_values.as_slice()), Err(CqfError::InvalidHeader(_))));
+        assert!(matches!(CQF::try_build(10, 10, HashMode::Fast)?.write_c_to(Vec::new()), Err(CqfError::IncompatibleFormat(_))));
+        assert!(matches!(CQF::read_c_from(&bytes[..1000]), Err(CqfError::Truncated)));
+        Ok(())
+    }
+
+    #[test]
+    fn fallible() -> Result<()> {
+        assert!(matches!(CQF::try_build(64, 64, HashMode::Fast), Err(CqfError::InvalidParameters(_))));
+        assert!(matches!(CQF::try_build(10, 63, HashMode::Fast), Err(CqfError::InvalidParameters(_))));
+        assert!(matches!(CQF::try_build(10, 12, HashMode::Fast), Err(CqfError::InvalidParameters(_))));
+        assert!(matches!(CQF::try_build(60, 60, HashMode::Fast), Err(CqfError::AllocationFailure)));
+        // no quotient bits would leave a 64-bit remainder
+        assert!(matches!(CQF::try_build(10, 0, HashMode::Fast), Err(CqfError::InvalidParameters(_))));
+        assert!(matches!(CQFOptions::new(10).key_bits(0).build(), Err(CqfError::InvalidParameters(_))));
+
+        let mut a = CQF::try_build(10, 10, HashMode::Invertible)?;
+        let b = CQF::try_build(10, 10, HashMode::None)?;
+        let c = CQFOptions::new(10).hash_mode(HashMode::Invertible).seed(7).build()?;
+        assert!(matches!(CQF::try_merge(&[&a, &b]), Err(CqfError::HashModeMismatch)));
+        assert!(matches!(CQF::try_merge(&[&a, &c]), Err(CqfError::HashModeMismatch)));
+        assert!(matches!(CQF::try_merge(&[]), Err(CqfError::InvalidParameters(_))));
+
+        // shrinking below what's in the filter fails and leaves it alone
+        for i in 0..500 {
+            a.insert(i, 1)?;
+        }
+        assert!(matches!(a.try_resize(6, 6), Err(CqfError::CapacityExceeded)));
+        assert!(matches!(a.try_resize(10, 11), Err(CqfError::InvalidParameters(_))));
+        a.try_resize(11, 11)?;
+        assert!((0..500).all(|i| a.query(i) == 1));
+
+        // 2-bit remainders can't give up a bit to grow, so this one eventually fills up for good
+        let mut full = CQFOptions::new(6).hash_mode(HashMode::None).remainder_bits(2).build()?;
+        let mut inserted = 0;
+        let err = loop {
+            match full.insert(inserted, 1) {
+                Ok(()) => inserted += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, CqfError::CapacityExceeded));
+        assert!((0..inserted).all(|i| full.query(i) == 1));
+        Ok(())
+    }
+
+    #[test]
+    fn resize_policy() -> Result<()> {
+        let grow = |threshold, growth_factor| ResizePolicy::Grow { threshold, growth_factor };
+        assert!(matches!(CQFOptions::new(10).hash_mode(HashMode::Fast).resize_policy(grow(1.5, 2)).build(), Err(CqfError::InvalidParameters(_))));
+        assert!(matches!(CQFOptions::new(10).hash_mode(HashMode::Fast).resize_policy(grow(0.9, 3)).build(), Err(CqfError::InvalidParameters(_))));
+
+        // a fixed-size filter stops taking items once it runs out of slots instead of growing
+        let mut fixed = CQFOptions::new(8).hash_mode(HashMode::Invertible).resize_policy(ResizePolicy::Disabled).build()?;
+        let mut inserted = 0;
+        let err = loop {
+            match fixed.insert(inserted, 1) {
+                Ok(()) => inserted += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, CqfError::CapacityExceeded));
+        assert_eq!(fixed.lognslots, 8);
+        assert!(inserted as f32 > 0.95 * fixed.xnslots as f32);
+        assert!((0..inserted).all(|i| fixed.query(i) == 1));
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut qf = CQFOptions::new(8).hash_mode(HashMode::Invertible).resize_policy(grow(0.5, 4)).build()?;
+        let hook_events = events.clone();
+        qf.on_resize(move |event| hook_events.lock().unwrap().push(event));
+        for i in 0..2000 {
+            qf.insert(i, 1)?;
+        }
+        assert!((0..2000).all(|i| qf.query(i) == 1));
+        // 256 slots grows to 1024 at half full and again to 4096, each reported before and after
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        assert!(matches!(events[0], ResizeEvent::Started { old_nslots: 256, new_nslots: 1024, load_factor } if load_factor >= 0.5));
+        assert!(matches!(events[1], ResizeEvent::Finished { old_nslots: 256, new_nslots: 1024, .. }));
+        assert!(matches!(events[3], ResizeEvent::Finished { old_nslots: 1024, new_nslots: 4096, .. }));
+        assert_eq!(qf.resize_policy(), grow(0.5, 4));
+        Ok(())
+    }
+
+    #[test]
+    fn options() -> Result<()> {
+        // every setting goes together with every other one
+        let options = CQFOptions::new(8)
+            .hash_mode(HashMode::Invertible)
+            .seed(7)
+            .value_bits(6)
+            .resize_policy(ResizePolicy::Grow { threshold: 0.5, growth_factor: 4 })
+            .value_merge(ValueMerge::Max);
+        let mut qf = options.build()?;
+        assert_eq!((qf.seed(), qf.value_bits(), qf.value_merge()), (7, 6, ValueMerge::Max));
+        assert_eq!(qf.remainder_bits, 64 - 8 - 6);
+        for i in 0..1000 {
+            qf.insert_kv(i, i % 64, 1)?;
+            qf.insert_kv(i, 63 - i % 64, 1)?;
+        }
+        assert_eq!(qf.lognslots, 12);
+        assert!((0..1000).all(|i| qf.query_value(i) == Some(((i % 64).max(63 - i % 64), 2))));
+        // described at the size it grew to, but hashing the same way
+        assert_eq!(qf.options(), options.lognslots(12).key_bits(12).remainder_bits(64 - 12 - 6));
+
+        let narrow = CQFOptions::new(10).key_bits(8).remainder_bits(20).value_bits(4).hash_mode(HashMode::None).build()?;
+        assert_eq!((narrow.quotient_bits, narrow.remainder_bits, narrow.value_bits), (8, 20, 4));
+        assert!(matches!(CQFOptions::new(10).value_bits(60).build(), Err(CqfError::InvalidParameters(_))));
+        assert!(matches!(CQFOptions::new(10).remainder_bits(60).build(), Err(CqfError::InvalidParameters(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn key_value() -> Result<()> {
+        let mut qf = CQFOptions::new(8).hash_mode(HashMode::Invertible).value_bits(9).build()?;
+        for i in 0..3000 {
+            qf.insert_kv(i, i % 512, i % 3 + 1)?;
+        }
+        // a couple of resizes later every key still has its value
+        assert!(qf.lognslots > 8);
+        for i in 0..3000 {
+            assert_eq!(qf.query_value(i), Some((i % 512, i % 3 + 1)));
+        }
+        assert_eq!(qf.query_value(5000), None);
+        assert!(qf.into_iter().all(|item| item.value == item.item.unwrap() % 512));
+        assert!(matches!(qf.insert_kv(1, 512, 1), Err(CqfError::InvalidParameters(_))));
+
+        // plain inserts and removes leave the value alone
+        qf.insert(7, 10)?;
+        qf.remove(8, 1)?;
+        assert_eq!(qf.query_value(7), Some((7, 12)));
+        assert_eq!(qf.query_value(8), Some((8, 2)));
+
+        let rules = [(ValueMerge::Keep, 0b0110), (ValueMerge::Replace, 0b0011), (ValueMerge::Min, 0b0011), (ValueMerge::Max, 0b0110), (ValueMerge::Or, 0b0111)];
+        for (merge, value) in rules {
+            let mut qf = CQFOptions::new(10).hash_mode(HashMode::Invertible).value_bits(4).build()?;
+            qf.set_value_merge(merge);
+            qf.insert_kv(42, 0b0110, 5)?;
+            qf.insert_kv(42, 0b0011, 1)?;
+            assert_eq!(qf.query_value(42), Some((value, 6)));
+        }
+        let mut strict = CQFOptions::new(10).hash_mode(HashMode::Invertible).value_bits(4).build()?;
+        strict.set_value_merge(ValueMerge::Reject);
+        strict.insert_kv(42, 3, 1)?;
+        strict.insert_kv(42, 3, 1)?;
+        assert!(matches!(strict.insert_kv(42, 4, 1), Err(CqfError::ValueCollision)));
+        assert_eq!(strict.query_value(42), Some((3, 2)));
+
+        // a new value that doesn't fit in a full fixed-size filter leaves the key as it was
+        let mut fixed = CQFOptions::new(8).hash_mode(HashMode::Invertible).value_bits(4).resize_policy(ResizePolicy::Disabled).value_merge(ValueMerge::Replace).build()?;
+        let mut inserted = 0;
+        while fixed.insert_kv(inserted, inserted % 8, 1).is_ok() {
+            inserted += 1;
+        }
+        let mut failed = 0;
+        for i in 0..inserted {
+            match fixed.insert_kv(i, 15, 1) {
+                Ok(()) => assert_eq!(fixed.query_value(i), Some((15, 2))),
+                Err(CqfError::CapacityExceeded) => {
+                    assert_eq!(fixed.query_value(i), Some((i % 8, 1)));
+                    failed += 1;
+                },
+                Err(err) => return Err(err.into()),
+            }
+        }
+        assert!(failed > 0);
+        assert_eq!(fixed.into_iter().count() as u64, inserted);
+
+        // the first filter's rule decides what happens to keys the filters share
+        let mut a = CQFOptions::new(10).hash_mode(HashMode::Invertible).value_bits(4).build()?;
+        let mut b = CQFOptions::new(10).hash_mode(HashMode::Invertible).value_bits(4).build()?;
+        a.set_value_merge(ValueMerge::Or);
+        for i in 0..500 {
+            a.insert_kv(i, 1, 1)?;
+            b.insert_kv(i + 250, 2, 2)?;
+        }
+        let merged = CQF::try_merge(&[&a, &b])?;
+        for i in 0..750 {
+            let expected = if i < 250 { (1, 1) } else if i < 500 { (3, 3) } else { (2, 2) };
+            assert_eq!(merged.query_value(i), Some(expected));
+        }
+        strict.insert_kv(300, 1, 1)?;
+        assert!(matches!(CQF::try_merge(&[&strict, &b]), Err(CqfError::ValueCollision)));
+        let no_values = CQFOptions::new(10).hash_mode(HashMode::Invertible).remainder_bits(50).build()?;
+        assert!(matches!(CQF::try_merge(&[&a, &no_values]), Err(CqfError::InvalidParameters(_))));
+
+        let read_qf = CQF::from_bytes(&merged.to_bytes()?)?;
+        assert_eq!(read_qf.value_bits(), 4);
+        assert!(read_qf.into_iter().eq(merged.into_iter()));
+        let mut c_bytes = Vec::new();
+        merged.write_c_to(&mut c_bytes)?;
+        assert!(CQF::read_c_from(c_bytes.as_slice())?.into_iter().eq(merged.into_iter()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn set_algebra() -> Result<()> {
+        let (mut a, mut b, mut c) = (CQF::try_build(12, 12, HashMode::Invertible)?, CQF::try_build(12, 12, HashMode::Invertible)?, CQF::try_build(12, 12, HashMode::Invertible)?);
+        for i in 0..1000 {
+            a.insert(i, 3)?;
+            b.insert(i + 500, 1)?;
+            c.insert(i + 800, 5)?;
+        }
+        let items = |qf: &CQF| qf.into_iter().map(|item| (item.item.unwrap(), item.count)).sorted().collect_vec();
+
+        assert_eq!(items(&CQF::intersect(&[&a, &b])?), (500..1000).map(|i| (i, 1)).collect_vec());
+        assert_eq!(items(&CQF::intersect(&[&a, &b, &c])?), (800..1000).map(|i| (i, 1)).collect_vec());
+        assert_eq!(items(&a.difference(&b)?), (0..500).map(|i| (i, 3)).collect_vec());
+        assert_eq!(items(&a.subtract(&b)?), (0..1000).map(|i| (i, if i < 500 { 3 } else { 2 })).collect_vec());
+        // counts that would go negative drop the key entirely
+        assert_eq!(items(&b.subtract(&a)?), (1000..1500).map(|i| (i, 1)).collect_vec());
+
+        let seeded = CQFOptions::new(12).hash_mode(HashMode::Invertible).seed(1).build()?;
+        assert!(matches!(a.difference(&seeded), Err(CqfError::HashModeMismatch)));
+        // only the hashes have to line up, not how they're split into quotient and remainder
+        assert_eq!(items(&a.subtract(&CQF::try_build(12, 10, HashMode::Invertible)?)?), items(&a));
+        assert!(matches!(CQF::intersect(&[&a, &CQF::try_build(12, 12, HashMode::None)?]), Err(CqfError::HashModeMismatch)));
+        assert!(matches!(CQF::intersect(&[]), Err(CqfError::InvalidParameters(_))));
+
+        // empty and one-key filters still come out with real fingerprints
+        let empty = CQF::try_build(10, 10, HashMode::Fast)?;
+        let mut one = CQF::try_build(10, 10, HashMode::Fast)?;
+        one.insert(4, 1)?;
+        let absent = |qf: &CQF| (0..1000).filter(|&i| i != 4).all(|i| qf.query(i) == 0);
+        for merged in [CQF::try_merge(&[&empty, &empty])?, CQF::intersect(&[&one, &empty])?, one.subtract(&one)?, empty.difference(&one)?] {
+            assert_eq!(merged.into_iter().count(), 0);
+            assert!(absent(&merged));
+        }
+        for merged in [CQF::try_merge(&[&one])?, CQF::try_merge(&[&one, &empty])?, CQF::intersect(&[&one, &one])?, one.subtract(&empty)?, one.difference(&empty)?] {
+            assert!(merged.query(4) > 0);
+            assert!(absent(&merged));
+        }
+        let mut merged = CQF::merge_with(&[&one, &empty], |counts| counts.first().copied())?;
+        merged.insert(12345, 1)?;
+        assert_eq!((merged.query(4), merged.query(12345), merged.query(99)), (1, 1, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn sorted_build() -> Result<()> {
+        let mut qf = CQF::try_build(14, 14, HashMode::Invertible)?;
+        for i in 0..10_000 {
+            qf.insert(i, i % 2 + 1)?;
+        }
+        assert_eq!(qf.lognslots, 14);
+        let items = qf.into_iter().collect_vec();
+        let built = CQF::from_sorted_iter(14, 14, HashMode::Invertible, items.iter().copied())?;
+        assert_eq!(built.noccupied_slots, qf.noccupied_slots);
+        assert!(built.into_iter().eq(qf.into_iter()));
+
+        // the offsets it works out have to hold up to inserts and removes afterwards
+        let mut built = CQF::from_sorted_iter(14, 12, HashMode::Invertible, items.iter().copied())?;
+        for i in 0..10_000 {
+            built.insert(i + 5000, 1)?;
+            built.remove(i, 1)?;
+        }
+        for i in 0..15_000 {
+            let expected = if i < 5000 { i % 2 } else if i < 10_000 { i % 2 + 1 } else { 1 };
+            assert_eq!(built.query(i), expected);
+        }
+
+        // repeated hashes add up, out of order ones are refused, and too many for the table don't fit
+        let twice = CQF::from_sorted_iter(15, 15, HashMode::Invertible, items.iter().flat_map(|&item| [item, item]))?;
+        assert!(twice.into_iter().map(|item| item.count).eq(items.iter().map(|item| 2 * item.count)));
+        assert!(matches!(CQF::from_sorted_iter(14, 14, HashMode::Invertible, items.iter().rev().copied()), Err(CqfError::InvalidParameters(_))));
+        assert!(matches!(CQF::from_sorted_iter(10, 10, HashMode::Invertible, items.iter().copied()), Err(CqfError::CapacityExceeded)));
+        Ok(())
+    }
+
+    #[test]
+    fn merge_with() -> Result<()> {
+        let mut qfs = Vec::new();
+        for n in 1..=3 {
+            let mut qf = CQF::try_build(12, 12, HashMode::Invertible)?;
+            for i in 0..1000 {
+                if i % n == 0 {
+                    qf.insert(i, n)?;
+                }
+            }
+            qfs.push(qf);
+        }
+        let qfs = qfs.iter().collect_vec();
+        let counts = |qf: &CQF| (0..1000).map(|i| qf.query(i)).collect_vec();
+
+        let max = CQF::merge_with(&qfs, |counts| counts.iter().copied().max())?;
+        assert_eq!(counts(&max), (0..1000).map(|i| if i % 3 == 0 { 3 } else if i % 2 == 0 { 2 } else { 1 }).collect_vec());
+        let min = CQF::merge_with(&qfs, |counts| counts.iter().copied().min())?;
+        assert_eq!(counts(&min), vec![1; 1000]);
+        // keys in at least two of the filters, counting how many they're in
+        let at_least_two = CQF::merge_with(&qfs, |counts| (counts.len() >= 2).then_some(counts.len() as u64))?;
+        assert_eq!(counts(&at_least_two), (0..1000).map(|i| [i % 2 == 0, i % 3 == 0].iter().filter(|&&b| b).count() as u64 + 1).map(|n| if n < 2 { 0 } else { n }).collect_vec());
+        assert_eq!(CQF::merge_with(&qfs, |_| None)?.into_iter().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_widths() -> Result<()> {
+        // different sizes and splits of the same hash width merge as they are
+        let (mut small, mut large) = (CQF::try_build(10, 10, HashMode::Invertible)?, CQF::try_build(12, 12, HashMode::Invertible)?);
+        for i in 0..500 {
+            small.insert(i, 1)?;
+            large.insert(i + 250, 2)?;
+        }
+        let merged = CQF::try_merge(&[&small, &large])?;
+        assert!((0..750).all(|i| merged.query(i) == if i < 250 { 1 } else if i < 500 { 3 } else { 2 }));
+
+        let (mut narrow, mut wide) = (CQFOptions::new(12).hash_mode(HashMode::Fast).remainder_bits(20).build()?, CQF::try_build(12, 12, HashMode::Fast)?);
+        for i in 0..2000 {
+            narrow.insert(i, 1)?;
+            wide.insert(i + 1000, 2)?;
+        }
+        assert!(matches!(CQF::try_merge(&[&narrow, &wide]), Err(CqfError::HashWidthMismatch)));
+        let (merged, report) = CQF::merge_truncated(&[&wide, &narrow])?;
+        assert_eq!((report.fingerprint_bits, report.widest_fingerprint_bits), (32, 64));
+        assert!(report.false_positive_rate > report.untruncated_false_positive_rate);
+        assert!((report.false_positive_rate - 3000.0 / 2f64.powi(32)).abs() < 1e-9);
+        // queries on the merged filter hash to the narrower width too
+        for i in 0..3000 {
+            assert!(merged.query(i) >= if i < 1000 { 1 } else if i < 2000 { 3 } else { 2 });
+        }
+
+        let invertible = CQFOptions::new(12).hash_mode(HashMode::Invertible).remainder_bits(20).build()?;
+        assert!(matches!(CQF::merge_truncated(&[&invertible, &small]), Err(CqfError::HashWidthMismatch)));
+        let (_, report) = CQF::merge_truncated(&[&small, &large])?;
+        assert_eq!(report.false_positive_rate, report.untruncated_false_positive_rate);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_files() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let path = |name: String| dir.join(format!("cqf-merge-files-test-{}-{}.cqf", std::process::id(), name));
+        // 40 inputs is more than get merged at once, so this goes through a level of temporary files
+        let mut qfs = Vec::new();
+        let mut inputs = Vec::new();
+        for n in 0..40u64 {
+            let mut qf = CQF::try_build(10 + n % 3, 10 + n % 3, HashMode::Invertible)?;
+            for i in 0..300 {
+                qf.insert(i * (n % 7 + 1) + n, i % 4 + 1 + if i % 50 == 0 { 1000 } else { 0 })?;
+            }
+            inputs.push(path(n.to_string()));
+            qf.serialize(inputs[n as usize].clone())?;
+            qfs.push(qf);
+        }
+        for (qf, input) in qfs.iter().zip(&inputs) {
+            let streamed: Vec<FilterItem> = CQFFileReader::open(input.clone())?.collect::<Result<_, _>>()?;
+            assert!(streamed.into_iter().eq(qf.into_iter()), "streaming a file should give the items it was written with!");
+        }
+
+        let expected = CQF::try_merge(&qfs.iter().collect_vec())?;
+        let output = path("out".to_string());
+        CQF::merge_files(&inputs, output.clone())?;
+        let merged = CQF::deserialize(output.clone())?;
+        assert_eq!(merged.noccupied_slots, expected.noccupied_slots);
+        assert!(merged.into_iter().eq(expected.into_iter()));
+        CQF::merge_files_in_groups(&inputs[..10], &output, 3)?;
+        assert!(CQF::deserialize(output.clone())?.into_iter().eq(CQF::try_merge(&qfs[..10].iter().collect_vec())?.into_iter()));
+        // only the output is left behind
+        assert!(!std::fs::read_dir(&dir)?.any(|entry| entry.is_ok_and(|entry| entry.file_name().to_string_lossy().contains(".merge-"))));
+
+        let mut corrupted = std::fs::read(&inputs[0])?;
+        *corrupted.last_mut().unwrap() ^= 1;
+        std::fs::write(&inputs[0], &corrupted)?;
+        assert!(matches!(CQFFileReader::open(inputs[0].clone())?.last(), Some(Err(CqfError::ChecksumMismatch("slots")))));
+        assert!(matches!(CQF::merge_files(&inputs[..2], output.clone()), Err(CqfError::ChecksumMismatch("slots"))));
+
+        let mut kv = CQFOptions::new(12).hash_mode(HashMode::Invertible).value_bits(8).build()?;
+        kv.insert_kv(7, 200, 3)?;
+        kv.serialize(inputs[1].clone())?;
+        assert!(matches!(CQF::merge_files(&inputs[1..3], output.clone()), Err(CqfError::InvalidParameters(_))));
+
+        let mut one = CQF::try_build(10, 10, HashMode::Fast)?;
+        one.insert(4, 1)?;
+        one.serialize(inputs[2].clone())?;
+        CQF::merge_files(&inputs[2..3], output.clone())?;
+        let merged = CQF::deserialize(output.clone())?;
+        assert_eq!(merged.query(4), 1);
+        assert!((0..1000).filter(|&i| i != 4).all(|i| merged.query(i) == 0));
+
+        for file in inputs.iter().chain([&output]) {
+            std::fs::remove_file(file)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn external_builder() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let output = dir.join(format!("cqf-external-test-{}.cqf", std::process::id()));
+        let options = CQFOptions::new(6).hash_mode(HashMode::Fast).seed(42);
+        // small enough that the inserts go out in a dozen runs, and are merged two at a time
+        let mut builder = ExternalBuilder::new(options, dir.clone(), 3 * (1 << 17))?;
+        let mut expected = CQFOptions::new(10).hash_mode(HashMode::Fast).seed(42).build()?;
+        for i in 0..100_000 {
+            builder.insert(i % 60_000, i % 3 + 1)?;
+            expected.insert(i % 60_000, i % 3 + 1)?;
+        }
+        builder.insert_key("key", 5)?;
+        expected.insert_key("key", 5)?;
+        builder.finish(output.clone())?;
+        let built = CQF::deserialize(output.clone())?;
+        assert_eq!(built.seed(), 42);
+        assert!(built.into_iter().eq(expected.into_iter()));
+        assert!(!std::fs::read_dir(&dir)?.any(|entry| entry.is_ok_and(|entry| entry.file_name().to_string_lossy().starts_with(&format!("cqf-run-{}-", std::process::id())))));
+
+        // a key's first value is the one kept, whichever runs its values went into
+        let options = CQFOptions::new(6).hash_mode(HashMode::Invertible).value_bits(8);
+        let mut builder = ExternalBuilder::new(options, dir.clone(), 3 * (1 << 17))?;
+        for i in 0..50_000 {
+            builder.insert_kv(i % 1000, (i / 1000) % 256, 1)?;
+        }
+        assert!(matches!(builder.insert_kv(1, 256, 1), Err(CqfError::InvalidParameters(_))));
+        builder.finish(output.clone())?;
+        let built = CQF::deserialize(output.clone())?;
+        assert!((0..1000).all(|i| built.query_value(i) == Some((0, 50))));
+
+        ExternalBuilder::new(options, dir.clone(), 3 * (1 << 17))?.finish(output.clone())?;
+        assert_eq!(CQF::deserialize(output.clone())?.into_iter().count(), 0);
+        // a single key still gets a table with quotient bits to put it in
+        let mut builder = ExternalBuilder::new(CQFOptions::new(6).hash_mode(HashMode::Fast), dir.clone(), 3 * (1 << 17))?;
+        builder.insert(4, 2)?;
+        builder.finish(output.clone())?;
+        let built = CQF::deserialize(output.clone())?;
+        assert_eq!(built.query(4), 2);
+        assert!((0..1000).filter(|&i| i != 4).all(|i| built.query(i) == 0));
+        assert!(matches!(ExternalBuilder::new(options, dir, 1000), Err(CqfError::InvalidParameters(_))));
+        std::fs::remove_file(output)?;
+        Ok(())
+    }
+
+    #[test]
+    fn insert_batch() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let items = (0..50_000).map(|_| rng.gen_range(0..20_000u64)).collect_vec();
+        let mut expected = CQF::try_build(8, 8, HashMode::Invertible)?;
+        for &item in &items {
+            expected.insert(item, 1)?;
+        }
+
+        // the whole batch into a small filter grows it once, straight to the size inserting one at a time ends up at
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let hook_events = events.clone();
+        let mut qf = CQF::try_build(8, 8, HashMode::Invertible)?;
+        qf.on_resize(move |event| hook_events.lock().unwrap().push(event));
+        qf.insert_batch(&items)?;
+        assert!(qf.into_iter().eq(expected.into_iter()));
+        assert_eq!(events.lock().unwrap().len(), 2);
+        assert!(matches!(events.lock().unwrap()[1], ResizeEvent::Finished { old_nslots: 256, new_nslots, .. } if new_nslots == expected.nslots));
+
+        // small batches into a big filter go in one at a time, in quotient order
+        let mut qf = CQF::try_build(8, 8, HashMode::Invertible)?;
+        for chunk in items.chunks(1000) {
+            qf.insert_batch(chunk)?;
+        }
+        assert!(qf.into_iter().eq(expected.into_iter()));
+
+        let counts = items.iter().map(|&item| (item, item % 3)).collect_vec();
+        let mut qf = CQF::try_build(8, 8, HashMode::Invertible)?;
+        qf.insert_counts(&counts[..100])?;
+        qf.insert_counts(&counts[100..])?;
+        assert!(items.iter().all(|&item| qf.query(item) == (item % 3) * items.iter().filter(|&&i| i == item).count() as u64));
+        assert!(matches!(qf.insert_counts(&[(1, u64::MAX), (1, 1)]), Err(CqfError::CountOverflow)));
+
+        // keys already in a key-value filter keep their value
+        let mut kv = CQFOptions::new(10).hash_mode(HashMode::Invertible).value_bits(4).build()?;
+        kv.insert_kv(7, 9, 1)?;
+        kv.insert_batch(&[7, 7, 8])?;
+        assert_eq!((kv.query_value(7), kv.query_value(8)), (Some((9, 3)), Some((0, 1))));
+        Ok(())
+    }
+
+    #[test]
+    fn query_batch() -> Result<()> {
+        let mut qf = CQF::try_build(16, 16, HashMode::Invertible)?;
+        for i in 0..30_000 {
+            qf.insert(i * 3, i % 4 + 1)?;
+        }
+        // a few keys go through prefetching, enough for a good share of the table in quotient order
+        for n in [5, 100, 20_000] {
+            let items = (0..n).map(|i| (i * 7919) % 100_000).collect_vec();
+            let mut counts = vec![u64::MAX; n as usize];
+            qf.query_batch(&items, &mut counts)?;
+            assert!(items.iter().zip(&counts).all(|(&item, &count)| count == qf.query(item)));
+        }
+        let mut counts = [0; 2];
+        qf.query_batch_by_hash(&[qf.calc_hash(3), qf.calc_hash(4)], &mut counts)?;
+        assert_eq!(counts, [2, 0]);
+        qf.query_batch(&[], &mut [])?;
+        // a short counts buffer is turned away untouched
+        assert!(matches!(qf.query_batch(&[3, 6, 9], &mut counts), Err(CqfError::InvalidParameters(_))));
+        assert!(matches!(qf.query_batch_by_hash(&[qf.calc_hash(3)], &mut counts), Err(CqfError::InvalidParameters(_))));
+        assert_eq!(counts, [2, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn range() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let mut qf = CQF::try_build(12, 12, HashMode::None)?;
+        let mut keys = std::collections::BTreeMap::new();
+        for _ in 0..3000 {
+            // clustered so runs spill over into the blocks after them
+            let key = rng.gen_range(0..64u64) << 58 | rng.gen_range(0..1000u64);
+            let count = rng.gen_range(1..4);
+            qf.insert(key, count)?;
+            *keys.entry(key).or_insert(0) += count;
+        }
+        let items = |range: std::ops::Range<u64>| keys.range(range).map(|(&key, &count)| (key, count)).collect_vec();
+        for _ in 0..200 {
+            let (a, b) = (rng.gen_range(0..64u64) << 58 | rng.gen_range(0..1000u64), rng.gen_range(0..64u64) << 58 | rng.gen_range(0..1000u64));
+            let (lo, hi) = (a.min(b), a.max(b));
+            assert_eq!(qf.range(lo..hi)?.map(|item| (item.item.unwrap(), item.count)).collect_vec(), items(lo..hi));
+        }
+        assert!(qf.range(..)?.eq(qf.into_iter()));
+        assert_eq!(qf.range(5..5)?.count(), 0);
+        assert!(qf.range(..=u64::MAX)?.map(|item| item.hash).eq(keys.keys().copied()));
+
+        let fast = CQF::try_build(12, 12, HashMode::Fast)?;
+        assert!(matches!(fast.range(0..10), Err(CqfError::InvalidParameters(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn ordered_queries() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let mut qf = CQF::try_build(12, 12, HashMode::None)?;
+        let mut keys = std::collections::BTreeMap::new();
+        for _ in 0..2000 {
+            let key = rng.gen_range(1..64u64) << 58 | rng.gen_range(0..1000u64);
+            let count = rng.gen_range(1..4);
+            qf.insert(key, count)?;
+            *keys.entry(key).or_insert(0) += count;
+        }
+        let key_of = |item: Option<FilterItem>| item.map(|item| item.hash);
+        for _ in 0..300 {
+            let x = rng.gen_range(0..64u64) << 58 | rng.gen_range(0..1000u64);
+            assert_eq!(key_of(qf.successor(x)?), keys.range(x..).next().map(|(&key, _)| key));
+            assert_eq!(key_of(qf.predecessor(x)?), keys.range(..=x).next_back().map(|(&key, _)| key));
+        }
+        // checked against going through every key in order
+        let index = qf.rank_index()?;
+        for _ in 0..300 {
+            let x = rng.gen_range(0..64u64) << 58 | rng.gen_range(0..1000u64);
+            assert_eq!(index.rank(x), qf.range(..=x)?.count() as u64);
+            assert_eq!(index.weighted_rank(x), qf.range(..=x)?.map(|item| item.count).sum::<u64>());
+        }
+        assert_eq!(index.rank(u64::MAX), keys.len() as u64);
+        let sorted = qf.range(..)?.collect_vec();
+        let copies = sorted.iter().flat_map(|&item| std::iter::repeat_n(item, item.count as usize)).collect_vec();
+        for k in [0, 1, 500, sorted.len() - 1, sorted.len()].into_iter().chain((0..100).map(|_| rng.gen_range(0..sorted.len()))) {
+            assert_eq!(index.select(k as u64), sorted.get(k).copied());
+        }
+        for k in [0, 1, 2, 1000, copies.len() - 1, copies.len()].into_iter().chain((0..100).map(|_| rng.gen_range(0..copies.len()))) {
+            assert_eq!(index.weighted_select(k as u64), copies.get(k).copied());
+        }
+        assert_eq!(CQF::try_build(8, 8, HashMode::None)?.rank_index()?.select(0), None);
+        // nothing has a quotient of 0, so there's nothing before the first key
+        assert_eq!(qf.predecessor(sorted[0].hash - 1)?, None);
+        assert_eq!(qf.successor(u64::MAX)?, None);
+
+        let fast = CQF::try_build(12, 12, HashMode::Fast)?;
+        assert!(matches!(fast.predecessor(10), Err(CqfError::InvalidParameters(_))));
+        assert!(matches!(fast.rank_index(), Err(CqfError::InvalidParameters(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn iter_from() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let mut qf = CQF::try_build(12, 12, HashMode::Fast)?;
+        for i in 0..3800 {
+            qf.insert(i, i % 3 + 1)?;
+        }
+        let items = qf.into_iter().collect_vec();
+        for _ in 0..200 {
+            let hash = if rng.gen_bool(0.5) { rng.gen() } else { items[rng.gen_range(0..items.len())].hash };
+            let expected = items.iter().copied().skip_while(|item| item.hash < hash);
+            assert!(qf.iter_from(hash).eq(expected.clone()));
+            // seeking works from anywhere in an iteration, backwards too
+            let mut iter = qf.into_iter();
+            iter.nth(rng.gen_range(0..items.len()));
+            iter.seek(hash);
+            assert!(iter.eq(expected));
+        }
+
+        // an export can pick up where it left off from the last hash it got to
+        let mut iter = qf.into_iter();
+        let mut exported = iter.by_ref().take(1000).collect_vec();
+        let resume = exported.last().unwrap().hash + 1;
+        exported.extend(qf.iter_from(resume));
+        assert_eq!(exported, items);
+        assert_eq!(qf.iter_from(items[0].hash).next(), Some(items[0]));
+        assert_eq!(CQF::try_build(8, 8, HashMode::Fast)?.iter_from(0).next(), None);
+
+        let narrow = CQFOptions::new(10).hash_mode(HashMode::Fast).remainder_bits(10).build()?;
+        assert_eq!(narrow.iter_from(1 << 20).next(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn par_chunks() -> Result<()> {
+        // clustered keys, so runs are shifted well past the quotients the chunks split at
+        let mut qf = CQF::try_build(12, 12, HashMode::None)?;
+        for i in 0..3000u64 {
+            qf.insert((i % 40) << 58 | i, i % 3 + 1)?;
+        }
+        let mut fast = CQF::try_build(14, 14, HashMode::Fast)?;
+        for i in 0..10_000 {
+            fast.insert(i, 1)?;
+        }
+        for qf in [&qf, &fast] {
+            for n in [1, 3, 8, 100, 5000] {
+                let chunks = qf.par_chunks(n);
+                assert_eq!(chunks.len(), n);
+                assert!(chunks.into_iter().flatten().eq(qf.into_iter()), "every item should be in exactly one chunk!");
+            }
+            let counts = qf.par_map_chunks(4, |chunk| chunk.map(|item| item.count).sum::<u64>());
+            assert_eq!(counts.len(), 4);
+            assert_eq!(counts.iter().sum::<u64>(), qf.into_iter().map(|item| item.count).sum::<u64>());
+        }
+        assert_eq!(qf.par_chunks(0).len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn range_filter() -> Result<()> {
+        let mut rng = rand::thread_rng();
+        let mut filter = RangeFilter::new(10, 12)?;
+        let keys: std::collections::BTreeSet<u64> = (0..5000).map(|_| rng.gen()).collect();
+        for &key in &keys {
+            filter.insert(key)?;
+        }
+        assert!(keys.iter().all(|&key| filter.may_contain(key) && filter.may_contain_range(key, key)));
+
+        // ranges with keys in them are never missed, and empty ones rarely turn up, long or short
+        let mut false_positives = 0;
+        let mut empty = 0;
+        for i in 0..20_000 {
+            let a: u64 = rng.gen();
+            let b = a.saturating_add(if i % 2 == 0 { rng.gen_range(0..1 << 20) } else { rng.gen_range(0..1 << 50) });
+            let present = keys.range(a..=b).next().is_some();
+            assert!(!present || filter.may_contain_range(a, b));
+            if !present {
+                empty += 1;
+                false_positives += filter.may_contain_range(a, b) as u64;
+            }
+        }
+        assert!((false_positives as f64) < 2.0 * filter.false_positive_rate() * empty as f64 + 10.0);
+        assert!(!filter.may_contain_range(10, 5));
+        assert!(filter.may_contain_range(0, u64::MAX));
+
+        // inserting keys again, or keys sharing a prefix, doesn't move the estimate
+        let rate = filter.false_positive_rate();
+        for &key in keys.iter().take(1000) {
+            filter.insert(key)?;
+            filter.insert(key ^ 1)?;
+        }
+        assert_eq!(filter.false_positive_rate(), rate);
+        assert_eq!(filter.filter().into_iter().map(|item| item.count).max(), Some(1));
+
+        // byte strings go in by their first 8 bytes, which keeps them in order
+        let mut words = RangeFilter::new(8, 40)?;
+        for word in ["apple", "banana", "cherry"] {
+            words.insert_bytes(word.as_bytes())?;
+        }
+        assert!(words.may_contain_bytes_range(b"b", b"bz"));
+        assert!(!words.may_contain_bytes_range(b"c", b"ch"));
+        assert!(!words.may_contain_bytes_range(b"d", b"zzz"));
+
+        let path = std::env::temp_dir().join(format!("cqf-range-filter-test-{}.cqf", std::process::id()));
+        filter.filter().serialize(path.clone())?;
+        let read = RangeFilter::from_filter(CQF::deserialize(path.clone())?)?;
+        assert!(keys.iter().all(|&key| read.may_contain(key)));
+        assert!(matches!(RangeFilter::from_filter(CQF::try_build(8, 8, HashMode::Fast)?), Err(CqfError::InvalidParameters(_))));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use itertools::{Either, Itertools};
+
+use crate::cqf::bitmask;
+use crate::{CQF, CqfError, FilterItem, HashMode};
+
+// how much a merge had to shorten fingerprints, and what that's estimated to do to the false
+// positive rate of the merged filter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TruncationReport {
+    pub fingerprint_bits: u64,
+    pub widest_fingerprint_bits: u64,
+    pub false_positive_rate: f64,
+    // what it would have been if every fingerprint had kept the widest width
+    pub untruncated_false_positive_rate: f64,
+}
+
+impl CQF {
+    // like try_merge, but filters that hash to different widths are brought down to the narrowest
+    // of them. Fast, Murmur and None hashes of a narrower width are the low bits of the wider ones,
+    // so that's just masking; invertible hashes are mixed at their own width and can't be.
+    // Truncated hashes lose their order, so the wider filters' items are sorted in memory
+    pub fn merge_truncated(qfs: &[&Self]) -> Result<(Self, TruncationReport), CqfError> {
+        let Some(narrowest) = qfs.iter().copied().min_by_key(|qf| qf.hash_bits()) else {
+            return Err(CqfError::InvalidParameters("there must be at least one CQF to combine"));
+        };
+        let widest_bits = qfs.iter().map(|qf| qf.hash_bits()).max().unwrap_or_default();
+        let bits = narrowest.hash_bits();
+        if qfs.iter().any(|qf| qf.hash_mode != narrowest.hash_mode || qf.seed != narrowest.seed) {
+            return Err(CqfError::HashModeMismatch);
+        }
+        // invertible hashes of different widths can't be truncated to each other
+        if bits != widest_bits && narrowest.hash_mode == HashMode::Invertible {
+            return Err(CqfError::HashWidthMismatch);
+        }
+        if qfs.iter().any(|qf| qf.value_bits != narrowest.value_bits) {
+            return Err(CqfError::InvalidParameters("CQFs must have the same value bits to be combined"));
+        }
+
+        let mask = bitmask(bits);
+        let truncated: Vec<Option<Vec<FilterItem>>> = qfs.iter().map(|qf| {
+            (qf.hash_bits() > bits).then(|| {
+                let mut items = qf.into_iter().map(|item| FilterItem { hash: item.hash & mask, item: None, ..item }).collect_vec();
+                items.sort_unstable_by_key(|item| item.hash);
+                items
+            })
+        }).collect();
+
+        let noccupied: u64 = qfs.iter().map(|qf| qf.noccupied_slots).sum();
+        let new = narrowest.build_sorted(noccupied, || {
+            qfs.iter().zip(&truncated).map(|(qf, items)| match items {
+                Some(items) => Either::Left(items.iter().copied()),
+                None => Either::Right(qf.into_iter()),
+            }).kmerge().map(Ok)
+        })?;
+
+        // a query for something that isn't there is a false positive if its fingerprint matches
+        // any of the n that are
+        let n = new.into_iter().count() as f64;
+        let false_positive_rate = |bits: u64| -(n * (-(0.5f64).powi(bits as i32)).ln_1p()).exp_m1();
+        let report = TruncationReport {
+            fingerprint_bits: bits,
+            widest_fingerprint_bits: widest_bits,
+            false_positive_rate: false_positive_rate(bits),
+            untruncated_false_positive_rate: false_positive_rate(widest_bits),
+        };
+        Ok((new, report))
+    }
+}
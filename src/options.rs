@@ -0,0 +1,120 @@
+use crate::{CQF, CqfError, HashMode, ResizePolicy, ValueMerge};
+
+// everything a filter is built with, set in any combination. Widths that aren't set are worked
+// out the way try_build does it: the quotient gets lognslots bits, and the remainder whatever is
+// left of a 64-bit slot after the quotient and the value
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct CQFOptions {
+    lognslots: u64,
+    key_bits: Option<u64>,
+    remainder_bits: Option<u64>,
+    value_bits: u64,
+    hash_mode: HashMode,
+    seed: u64,
+    resize_policy: ResizePolicy,
+    value_merge: ValueMerge,
+}
+
+impl CQFOptions {
+    pub fn new(lognslots: u64) -> Self {
+        Self {
+            lognslots,
+            key_bits: None,
+            remainder_bits: None,
+            value_bits: 0,
+            hash_mode: HashMode::default(),
+            seed: 0,
+            resize_policy: ResizePolicy::default(),
+            value_merge: ValueMerge::default(),
+        }
+    }
+
+    pub fn lognslots(mut self, lognslots: u64) -> Self {
+        self.lognslots = lognslots;
+        self
+    }
+
+    // how many bits of the hash pick the quotient
+    pub fn key_bits(mut self, key_bits: u64) -> Self {
+        self.key_bits = Some(key_bits);
+        self
+    }
+
+    pub fn remainder_bits(mut self, remainder_bits: u64) -> Self {
+        self.remainder_bits = Some(remainder_bits);
+        self
+    }
+
+    // every key gets a value_bits-wide value stored next to its fingerprint, on top of its count.
+    // Unless the remainder is set too, the value comes out of it so slots still fit in 64 bits
+    pub fn value_bits(mut self, value_bits: u64) -> Self {
+        self.value_bits = value_bits;
+        self
+    }
+
+    pub fn hash_mode(mut self, hash_mode: HashMode) -> Self {
+        self.hash_mode = hash_mode;
+        self
+    }
+
+    // seeds the xxh3 hashes of Fast mode and of keys, and Murmur's
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn resize_policy(mut self, resize_policy: ResizePolicy) -> Self {
+        self.resize_policy = resize_policy;
+        self
+    }
+
+    pub fn value_merge(mut self, value_merge: ValueMerge) -> Self {
+        self.value_merge = value_merge;
+        self
+    }
+
+    pub fn build(&self) -> Result<CQF, CqfError> {
+        let (quotient_bits, remainder_bits) = self.widths()?;
+        let mut qf = CQF::new_table(self.lognslots, quotient_bits, remainder_bits, self.value_bits, self.hash_mode)?;
+        self.apply(&mut qf)?;
+        Ok(qf)
+    }
+
+    pub(crate) fn layout(&self) -> Result<CQF, CqfError> {
+        let (quotient_bits, remainder_bits) = self.widths()?;
+        let mut qf = CQF::new_layout(self.lognslots, quotient_bits, remainder_bits, self.value_bits, self.hash_mode)?;
+        self.apply(&mut qf)?;
+        Ok(qf)
+    }
+
+    fn widths(&self) -> Result<(u64, u64), CqfError> {
+        let quotient_bits = self.key_bits.unwrap_or(self.lognslots);
+        let remainder_bits = match self.remainder_bits {
+            Some(remainder_bits) => remainder_bits,
+            None => 64u64.checked_sub(quotient_bits).and_then(|bits| bits.checked_sub(self.value_bits))
+                .ok_or(CqfError::InvalidParameters("key and value bits must fit in a 64-bit slot"))?,
+        };
+        Ok((quotient_bits, remainder_bits))
+    }
+
+    fn apply(&self, qf: &mut CQF) -> Result<(), CqfError> {
+        qf.set_resize_policy(self.resize_policy)?;
+        qf.seed = self.seed;
+        qf.value_merge = self.value_merge;
+        Ok(())
+    }
+}
+
+impl CQF {
+    // the options this filter was built with, at its current size
+    pub fn options(&self) -> CQFOptions {
+        CQFOptions::new(self.lognslots)
+            .key_bits(self.quotient_bits)
+            .remainder_bits(self.remainder_bits)
+            .value_bits(self.value_bits)
+            .hash_mode(self.hash_mode)
+            .seed(self.seed)
+            .resize_policy(self.resize_policy)
+            .value_merge(self.value_merge)
+    }
+}
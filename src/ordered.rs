@@ -0,0 +1,145 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::cqf::{bitmask, bitrank, bitselect};
+use crate::{CQF, CQFIterator, CqfError, FilterItem, HashMode};
+
+// how many keys (and copies of keys) have quotients in the blocks before each block, so rank and
+// select only have to decode the one block their answer is in
+pub struct RankIndex<'a> {
+    qf: &'a CQF,
+    keys: Vec<u64>,
+    weights: Vec<u64>,
+}
+
+impl RankIndex<'_> {
+    // how many keys there are at or before x
+    pub fn rank(&self, x: u64) -> u64 {
+        self.rank_with(&self.keys, x, |_| 1)
+    }
+
+    // the same, counting every key as many times as it was inserted
+    pub fn weighted_rank(&self, x: u64) -> u64 {
+        self.rank_with(&self.weights, x, |item| item.count)
+    }
+
+    // the key with k keys before it
+    pub fn select(&self, k: u64) -> Option<FilterItem> {
+        let (before, mut items) = self.select_block(&self.keys, k)?;
+        items.nth((k - before) as usize)
+    }
+
+    // the key that the k-th of all the inserted copies of keys is a copy of
+    pub fn weighted_select(&self, k: u64) -> Option<FilterItem> {
+        let (mut before, items) = self.select_block(&self.weights, k)?;
+        for item in items {
+            before += item.count;
+            if k < before {
+                return Some(item);
+            }
+        }
+        None
+    }
+
+    fn rank_with(&self, totals: &[u64], x: u64, weight: impl Fn(&FilterItem) -> u64) -> u64 {
+        let x = x.min(bitmask(self.qf.hash_bits()));
+        let quotient = self.qf.calc_qr(x).0;
+        let block_idx = quotient / 64;
+        // runs for quotients past x's can't hold anything at or before it
+        if self.qf.blocks[block_idx].occupieds & bitmask(quotient as u64 % 64 + 1) == 0 {
+            return totals[block_idx];
+        }
+        totals[block_idx] + self.qf.iter_from_quotient(64 * block_idx).take_while(|item| item.hash <= x).map(|item| weight(&item)).sum::<u64>()
+    }
+
+    // the block whose runs hold the k-th key, how many come before it and its runs
+    fn select_block(&self, totals: &[u64], k: u64) -> Option<(u64, CQFIterator<'_>)> {
+        let block_idx = totals.partition_point(|&total| total <= k) - 1;
+        (block_idx + 1 < totals.len()).then(|| (totals[block_idx], self.qf.iter_from_quotient(64 * block_idx)))
+    }
+}
+
+impl CQF {
+    // with HashMode::None a key is its own hash, so the filter holds its keys in order and can
+    // hand back the ones in a range (each with its count) without scanning up to it
+    pub fn range<R: RangeBounds<u64>>(&self, range: R) -> Result<impl Iterator<Item = FilterItem> + '_, CqfError> {
+        self.check_ordered()?;
+        // keys past the hash width can't be in the filter, so the range stops there. An empty
+        // range is left as one nothing can be in
+        let (lo, hi) = self.inclusive_bounds(range).unwrap_or((1, 0));
+        Ok(self.iter_from(lo).take_while(move |item| item.hash <= hi))
+    }
+
+    // the first key at or after x
+    pub fn successor(&self, x: u64) -> Result<Option<FilterItem>, CqfError> {
+        Ok(self.range(x..)?.next())
+    }
+
+    // the last key at or before x. Runs are only decoded forwards, so this goes back to the last
+    // quotient that has one and reads its run up to x
+    pub fn predecessor(&self, x: u64) -> Result<Option<FilterItem>, CqfError> {
+        self.check_ordered()?;
+        let x = x.min(bitmask(self.hash_bits()));
+        let mut quotient = self.calc_qr(x).0;
+        while let Some(occupied) = self.last_occupied_at_or_before(quotient) {
+            let last = self.iter_from_quotient(occupied).take_while(|item| self.calc_qr(item.hash).0 == occupied && item.hash <= x).last();
+            // only x's own run can start past it, so the run before that always has one
+            if last.is_some() || occupied == 0 {
+                return Ok(last);
+            }
+            quotient = occupied - 1;
+        }
+        Ok(None)
+    }
+
+    // an index for rank and select queries, built in one pass over the filter. It borrows the
+    // filter, so it can't go stale
+    pub fn rank_index(&self) -> Result<RankIndex<'_>, CqfError> {
+        self.check_ordered()?;
+        let nblocks = self.nblocks as usize;
+        let (mut keys, mut weights) = (vec![0u64; nblocks + 1], vec![0u64; nblocks + 1]);
+        for item in self {
+            let block_idx = self.calc_qr(item.hash).0 / 64;
+            keys[block_idx + 1] += 1;
+            weights[block_idx + 1] = weights[block_idx + 1].checked_add(item.count).ok_or(CqfError::CountOverflow)?;
+        }
+        for block_idx in 0..nblocks {
+            keys[block_idx + 1] += keys[block_idx];
+            weights[block_idx + 1] = weights[block_idx + 1].checked_add(weights[block_idx]).ok_or(CqfError::CountOverflow)?;
+        }
+        Ok(RankIndex { qf: self, keys, weights })
+    }
+
+    fn check_ordered(&self) -> Result<(), CqfError> {
+        if self.hash_mode != HashMode::None {
+            return Err(CqfError::InvalidParameters("ordered queries need HashMode::None"));
+        }
+        Ok(())
+    }
+
+    fn last_occupied_at_or_before(&self, quotient: usize) -> Option<usize> {
+        let block_idx = quotient / 64;
+        let rank = bitrank(self.blocks[block_idx].occupieds, quotient % 64);
+        if rank > 0 {
+            return Some(64 * block_idx + bitselect(self.blocks[block_idx].occupieds, rank - 1));
+        }
+        (0..block_idx).rev().find_map(|block_idx| {
+            let occupieds = self.blocks[block_idx].occupieds;
+            (occupieds != 0).then(|| 64 * block_idx + 63 - occupieds.leading_zeros() as usize)
+        })
+    }
+
+    // the smallest and largest hashes in the range, or None if there aren't any
+    fn inclusive_bounds<R: RangeBounds<u64>>(&self, range: R) -> Option<(u64, u64)> {
+        let lo = match range.start_bound() {
+            Bound::Included(&lo) => lo,
+            Bound::Excluded(&lo) => lo.checked_add(1)?,
+            Bound::Unbounded => 0,
+        };
+        let hi = match range.end_bound() {
+            Bound::Included(&hi) => hi,
+            Bound::Excluded(&hi) => hi.checked_sub(1)?,
+            Bound::Unbounded => u64::MAX,
+        }.min(bitmask(self.hash_bits()));
+        (lo <= hi).then_some((lo, hi))
+    }
+}
@@ -0,0 +1,44 @@
+use crate::{CQF, CQFIterator, FilterItem};
+
+// the items of one range of quotients. Every item belongs to its quotient's run wherever the run
+// has been shifted to, so chunks split at any quotients between them have each item exactly once
+pub struct CQFChunk<'a> {
+    qf: &'a CQF,
+    iter: CQFIterator<'a>,
+    end: usize,
+}
+
+impl Iterator for CQFChunk<'_> {
+    type Item = FilterItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.iter.next()?;
+        // every item after the first one past the end is past it too
+        (self.qf.calc_qr(item.hash).0 < self.end).then_some(item)
+    }
+}
+
+impl CQF {
+    // splits the filter into n chunks of about the same number of quotients, in hash order, for
+    // iterating over on separate threads
+    pub fn par_chunks(&self, n: usize) -> Vec<CQFChunk<'_>> {
+        let n = n.max(1);
+        let nquotients = 1usize << self.quotient_bits;
+        let bound = |i: usize| (i as u128 * nquotients as u128 / n as u128) as usize;
+        (0..n).map(|i| CQFChunk { qf: self, iter: self.iter_from_quotient(bound(i)), end: bound(i + 1) }).collect()
+    }
+
+    // runs f over each of n chunks on its own scoped thread, giving back what it returned for each
+    // chunk in hash order
+    pub fn par_map_chunks<R, F>(&self, n: usize, f: F) -> Vec<R>
+    where
+        R: Send,
+        F: Fn(CQFChunk<'_>) -> R + Sync,
+    {
+        let f = &f;
+        std::thread::scope(|s| {
+            let handles: Vec<_> = self.par_chunks(n).into_iter().map(|chunk| s.spawn(move || f(chunk))).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap_or_else(|err| std::panic::resume_unwind(err))).collect()
+        })
+    }
+}
@@ -0,0 +1,78 @@
+use crate::{CQF, CQFOptions, CqfError, HashMode};
+
+// answers whether any key in a range might be there, in the spirit of prefix and memento filters.
+// Keys are kept as their top quotient + remainder bits in a HashMode::None filter, so the
+// quotient is an order-preserving prefix and a range of keys is a range of the filter. The only
+// false positives are keys sharing the truncated prefix of one of the range's ends, which keeps
+// the rate at about 2n / 2^(quotient + remainder bits) for evenly spread keys, however long the
+// range. Growing moves bits from the remainder to the quotient without changing the truncation
+pub struct RangeFilter {
+    qf: CQF,
+}
+
+impl RangeFilter {
+    pub fn new(lognslots: u64, remainder_bits: u64) -> Result<Self, CqfError> {
+        Ok(Self { qf: CQFOptions::new(lognslots).remainder_bits(remainder_bits).hash_mode(HashMode::None).build()? })
+    }
+
+    // takes back a filter from filter(), after it's been written out and read in again
+    pub fn from_filter(qf: CQF) -> Result<Self, CqfError> {
+        if qf.hash_mode != HashMode::None {
+            return Err(CqfError::InvalidParameters("range filters need HashMode::None"));
+        }
+        Ok(Self { qf })
+    }
+
+    // only membership is asked of it, so a prefix that's already there isn't counted again and
+    // doesn't take up more slots or raise the false positive estimate
+    pub fn insert(&mut self, key: u64) -> Result<(), CqfError> {
+        let prefix = self.prefix(key);
+        if self.qf.query(prefix) > 0 {
+            return Ok(());
+        }
+        self.qf.insert(prefix, 1)
+    }
+
+    // byte string keys are ordered by their first 8 bytes, which keeps them in order for ranges
+    pub fn insert_bytes(&mut self, key: &[u8]) -> Result<(), CqfError> {
+        self.insert(bytes_prefix(key))
+    }
+
+    pub fn may_contain(&self, key: u64) -> bool {
+        self.qf.query(self.prefix(key)) > 0
+    }
+
+    // whether a key in [a, b] might have been inserted. False means none was
+    pub fn may_contain_range(&self, a: u64, b: u64) -> bool {
+        if a > b {
+            return false;
+        }
+        let hi = self.prefix(b);
+        self.qf.iter_from(self.prefix(a)).next().is_some_and(|item| item.hash <= hi)
+    }
+
+    pub fn may_contain_bytes_range(&self, a: &[u8], b: &[u8]) -> bool {
+        a <= b && self.may_contain_range(bytes_prefix(a), bytes_prefix(b))
+    }
+
+    // the bound on the false positive rate of a range query, for evenly spread keys
+    pub fn false_positive_rate(&self) -> f64 {
+        (2.0 * self.qf.noccupied_slots as f64 / 2f64.powi(self.qf.hash_bits() as i32)).min(1.0)
+    }
+
+    // the underlying filter, for serializing it
+    pub fn filter(&self) -> &CQF {
+        &self.qf
+    }
+
+    fn prefix(&self, key: u64) -> u64 {
+        key >> (64 - self.qf.hash_bits())
+    }
+}
+
+fn bytes_prefix(key: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    let len = key.len().min(8);
+    bytes[..len].copy_from_slice(&key[..len]);
+    u64::from_be_bytes(bytes)
+}
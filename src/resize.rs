@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::CqfError;
+
+// when inserts grow the table. Tables are a power of two in size, so the growth factor has to be
+// one too, and every doubling moves one bit of the hash from the remainder into the quotient
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ResizePolicy {
+    Grow { threshold: f32, growth_factor: u64 },
+    // the table stays the size it was built at, and inserts that don't fit fail with CapacityExceeded
+    Disabled,
+}
+
+impl Default for ResizePolicy {
+    fn default() -> Self {
+        ResizePolicy::Grow { threshold: 0.95, growth_factor: 2 }
+    }
+}
+
+impl ResizePolicy {
+    pub(crate) fn validate(&self) -> Result<(), CqfError> {
+        if let ResizePolicy::Grow { threshold, growth_factor } = *self {
+            if !(threshold > 0.0 && threshold <= 1.0) {
+                return Err(CqfError::InvalidParameters("resize threshold must be in (0, 1]"));
+            }
+            if growth_factor < 2 || !growth_factor.is_power_of_two() {
+                return Err(CqfError::InvalidParameters("growth factor must be a power of two of at least 2"));
+            }
+        }
+        Ok(())
+    }
+
+    // how many bits lognslots goes up by each time the table grows
+    pub(crate) fn growth_bits(&self) -> Option<u64> {
+        match self {
+            ResizePolicy::Grow { growth_factor, .. } => Some(growth_factor.ilog2() as u64),
+            ResizePolicy::Disabled => None,
+        }
+    }
+}
+
+// reported to the resize hook around every resize an insert sets off, since the insert blocks
+// until the whole table has been rebuilt
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ResizeEvent {
+    Started { old_nslots: u64, new_nslots: u64, load_factor: f32 },
+    Finished { old_nslots: u64, new_nslots: u64, elapsed: Duration },
+}
+
+pub(crate) type ResizeHook = Arc<dyn Fn(ResizeEvent) + Send + Sync>;
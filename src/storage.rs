@@ -0,0 +1,87 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use crate::CqfError;
+
+// the backing memory for a CQF's blocks or slots: either owned, or a section of a mapped file
+// that only ever gets handed out read-only through a CQFView
+pub(crate) struct Storage<T> {
+    backing: Backing<T>,
+    // cached so reads don't have to check which kind of backing this is. Everything, slices
+    // included, goes through it, so get and set never race a reference made some other way
+    ptr: *mut T,
+    len: usize,
+}
+
+// either way, only held to keep the memory under ptr alive
+enum Backing<T> {
+    Owned(#[allow(dead_code)] Vec<T>),
+    Mapped(#[allow(dead_code)] Arc<Mmap>),
+}
+
+// the pointer is only ever into the backing, which is owned along with it. Writing through a
+// shared Storage takes set, which is unsafe
+unsafe impl<T: Send> Send for Storage<T> {}
+unsafe impl<T: Sync> Sync for Storage<T> {}
+
+impl<T> Storage<T> {
+    pub(crate) fn owned(mut vec: Vec<T>) -> Self {
+        Self { ptr: vec.as_mut_ptr(), len: vec.len(), backing: Backing::Owned(vec) }
+    }
+
+    pub(crate) fn mapped(map: Arc<Mmap>, offset: usize, len: usize) -> Result<Self, CqfError> {
+        let end = len.checked_mul(std::mem::size_of::<T>()).and_then(|bytes| bytes.checked_add(offset));
+        if end.is_none_or(|end| end > map.len()) {
+            return Err(CqfError::Truncated);
+        }
+        if !(map.as_ptr() as usize + offset).is_multiple_of(std::mem::align_of::<T>()) {
+            return Err(CqfError::InvalidHeader("section alignment"));
+        }
+        // never written through, see set
+        let ptr = unsafe { map.as_ptr().add(offset) as *mut T };
+        Ok(Self { ptr, len, backing: Backing::Mapped(map) })
+    }
+}
+
+// single elements are read and written without making a slice of the whole storage, so threads
+// working on different elements never hold references that overlap
+impl<T: Copy> Storage<T> {
+    pub(crate) fn get(&self, index: usize) -> T {
+        assert!(index < self.len, "storage index out of bounds!");
+        unsafe { self.ptr.add(index).read() }
+    }
+
+    // SAFETY: the storage must be owned, and for as long as the write takes nothing else may be
+    // reading or writing this element or holding a slice of the storage
+    pub(crate) unsafe fn set(&self, index: usize, val: T) {
+        assert!(index < self.len, "storage index out of bounds!");
+        debug_assert!(matches!(self.backing, Backing::Owned(_)), "mapped CQFs are read-only!");
+        unsafe { self.ptr.add(index).write(val) }
+    }
+}
+
+impl<T> Default for Storage<T> {
+    fn default() -> Self {
+        Self::owned(Vec::new())
+    }
+}
+
+impl<T> Deref for Storage<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // bounds and alignment were checked when the storage was made, and it never reallocates
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl<T> DerefMut for Storage<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match &self.backing {
+            // the pointer came from the vec, which is only ever reached through it
+            Backing::Owned(_) => unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) },
+            Backing::Mapped(_) => panic!("mapped CQFs are read-only!")
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use itertools::Itertools;
+use xxhash_rust::xxh3::Xxh3;
+use crate::cqf::{bitmask, get_bits, set_bits};
+use crate::format::{self, Sections};
+use crate::{Block, CQF, CqfError, FilterItem};
+
+// how many files are merged at once. Merges of more go through temporary files a level at a
+// time, so only this many readers (and their buffers) are ever open together
+pub(crate) const MERGE_FAN_IN: usize = 32;
+const BUFFER_LEN: usize = 1 << 16;
+// roughly what an open reader takes up, its two buffers being most of it
+pub(crate) const READER_MEMORY: usize = 2 * BUFFER_LEN;
+
+// a block's slots take up exactly slot_bits words, so each block can be read and written with
+// its slots as one unit
+struct BlockSlots {
+    block: Block,
+    words: Vec<u64>,
+}
+
+// the items of a filter file in hash order, read a run at a time. Only the blocks the current run
+// spans are held in memory, and the sections are checksummed as they go by, the last item being
+// followed by an error instead if either doesn't match
+pub struct CQFFileReader {
+    qf: CQF,
+    sections: Sections,
+    blocks: BufReader<File>,
+    slots: BufReader<File>,
+    blocks_hasher: Xxh3,
+    slots_hasher: Xxh3,
+    window: VecDeque<BlockSlots>,
+    first_block: usize,
+    quotients: VecDeque<usize>,
+    position: usize,
+    run: Vec<u64>,
+    items: VecDeque<FilterItem>,
+    done: bool,
+}
+
+impl CQFFileReader {
+    pub fn open(path: PathBuf) -> Result<Self, CqfError> {
+        let mut header = BufReader::new(File::open(&path)?);
+        let (qf, sections) = format::read_layout(&mut header)?;
+        let mut blocks = File::open(&path)?;
+        blocks.seek(SeekFrom::Start(sections.blocks_offset))?;
+        let mut slots = File::open(&path)?;
+        slots.seek(SeekFrom::Start(sections.slots_offset))?;
+        Ok(Self {
+            qf,
+            sections,
+            blocks: BufReader::with_capacity(BUFFER_LEN, blocks),
+            slots: BufReader::with_capacity(BUFFER_LEN, slots),
+            blocks_hasher: Xxh3::new(),
+            slots_hasher: Xxh3::new(),
+            window: VecDeque::new(),
+            first_block: 0,
+            quotients: VecDeque::new(),
+            position: 0,
+            run: Vec::new(),
+            items: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    // the filter's parameters, without its table
+    pub(crate) fn layout(&self) -> &CQF {
+        &self.qf
+    }
+
+    fn read_block(&mut self) -> Result<(), CqfError> {
+        let mut block = [Block::default()];
+        self.blocks.read_exact(format::as_bytes_mut(&mut block))?;
+        self.blocks_hasher.update(format::as_bytes(&block));
+        let mut words = vec![0; self.qf.slot_bits() as usize];
+        self.slots.read_exact(format::as_bytes_mut(&mut words))?;
+        self.slots_hasher.update(format::as_bytes(&words));
+
+        let first_slot = 64 * (self.first_block + self.window.len());
+        let mut occupieds = block[0].occupieds;
+        while occupieds != 0 {
+            self.quotients.push_back(first_slot + occupieds.trailing_zeros() as usize);
+            occupieds &= occupieds - 1;
+        }
+        self.window.push_back(BlockSlots { block: block[0], words });
+        Ok(())
+    }
+
+    fn blocks_read(&self) -> usize {
+        self.first_block + self.window.len()
+    }
+
+    // decodes the next quotient's run into items, or checks the checksums once there are none left
+    fn next_run(&mut self) -> Result<bool, CqfError> {
+        while self.quotients.is_empty() && self.blocks_read() < self.qf.nblocks as usize {
+            self.read_block()?;
+        }
+        let Some(quotient) = self.quotients.pop_front() else {
+            if self.blocks_hasher.digest() != self.sections.blocks_checksum {
+                return Err(CqfError::ChecksumMismatch("blocks"));
+            }
+            if self.slots_hasher.digest() != self.sections.slots_checksum {
+                return Err(CqfError::ChecksumMismatch("slots"));
+            }
+            return Ok(false);
+        };
+
+        // runs are laid out one after another, each starting at its quotient or just past the last
+        let bits = self.qf.slot_bits() as usize;
+        let mut index = self.position.max(quotient);
+        self.run.clear();
+        loop {
+            while index / 64 >= self.blocks_read() {
+                if self.blocks_read() == self.qf.nblocks as usize {
+                    // the run goes off the end of the table, which only a damaged file can do
+                    return Err(CqfError::ChecksumMismatch("blocks"));
+                }
+                self.read_block()?;
+            }
+            let block = &self.window[index / 64 - self.first_block];
+            self.run.push(get_bits(&block.words, (index % 64) * bits, bits));
+            if block.block.is_runend(index % 64) {
+                break;
+            }
+            index += 1;
+        }
+        self.position = index + 1;
+        // nothing before the next free slot is needed again
+        while self.first_block < self.position / 64 && !self.window.is_empty() {
+            self.window.pop_front();
+            self.first_block += 1;
+        }
+
+        let (mut slot, mut count) = (0, 0);
+        let mut i = 0;
+        while i < self.run.len() {
+            let end = self.qf.decode_run(&self.run, i, &mut slot, &mut count);
+            let hash = self.qf.build_hash(quotient, slot >> self.qf.value_bits);
+            self.items.push_back(FilterItem { hash, item: self.qf.invert_hash(hash), count, value: slot & bitmask(self.qf.value_bits) });
+            i = end + 1;
+        }
+        Ok(true)
+    }
+}
+
+impl Iterator for CQFFileReader {
+    type Item = Result<FilterItem, CqfError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.items.is_empty() && !self.done {
+            match self.next_run() {
+                Ok(true) => {},
+                Ok(false) => self.done = true,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                },
+            }
+        }
+        self.items.pop_front().map(Ok)
+    }
+}
+
+// writes items in hash order straight into a filter file, laying them out the way fill_sorted
+// does. A block goes out as soon as no later run can reach it, so only the blocks the current run
+// spans are held in memory
+pub(crate) struct SortedWriter {
+    qf: CQF,
+    header: BufWriter<File>,
+    blocks: BufWriter<File>,
+    slots: BufWriter<File>,
+    blocks_hasher: Xxh3,
+    slots_hasher: Xxh3,
+    window: VecDeque<BlockSlots>,
+    first_block: usize,
+    next_free: usize,
+    run_quotient: Option<usize>,
+}
+
+impl SortedWriter {
+    // qf only gives the layout to write, its table isn't used
+    pub(crate) fn create(path: &Path, qf: CQF) -> Result<Self, CqfError> {
+        format::check_endianness()?;
+        let (blocks_offset, slots_offset) = format::section_offsets(&qf);
+        // separate handles rather than clones, which would share one cursor
+        let header = File::create(path)?;
+        let mut blocks = OpenOptions::new().write(true).open(path)?;
+        blocks.seek(SeekFrom::Start(blocks_offset as u64))?;
+        let mut slots = OpenOptions::new().write(true).open(path)?;
+        slots.seek(SeekFrom::Start(slots_offset as u64))?;
+        Ok(Self {
+            qf,
+            header: BufWriter::new(header),
+            blocks: BufWriter::with_capacity(BUFFER_LEN, blocks),
+            slots: BufWriter::with_capacity(BUFFER_LEN, slots),
+            blocks_hasher: Xxh3::new(),
+            slots_hasher: Xxh3::new(),
+            window: VecDeque::new(),
+            first_block: 0,
+            next_free: 0,
+            run_quotient: None,
+        })
+    }
+
+    // writes the items (in hash order, equal hashes combined like a merge would) and then the
+    // header, leaving a complete file
+    pub(crate) fn write_all<I: Iterator<Item = Result<FilterItem, CqfError>>>(mut self, items: I) -> Result<(), CqfError> {
+        for item in self.qf.coalesce_sorted(items) {
+            self.append(item?)?;
+        }
+        self.finish()
+    }
+
+    fn block_mut(&mut self, block_idx: usize) -> &mut BlockSlots {
+        while self.first_block + self.window.len() <= block_idx {
+            let words = vec![0; self.qf.slot_bits() as usize];
+            self.window.push_back(BlockSlots { block: Block::default(), words });
+        }
+        &mut self.window[block_idx - self.first_block]
+    }
+
+    fn append(&mut self, item: FilterItem) -> Result<(), CqfError> {
+        if item.count == 0 {
+            return Ok(());
+        }
+        let (quotient, remainder) = self.qf.calc_qr(item.hash);
+        let slots = self.qf.encode_counter((remainder << self.qf.value_bits) | item.value, item.count);
+        let continues_run = self.run_quotient == Some(quotient);
+        let start = if continues_run { self.next_free } else { self.next_free.max(quotient) };
+        if start + slots.len() > self.qf.xnslots as usize {
+            return Err(CqfError::CapacityExceeded);
+        }
+        if continues_run {
+            let last = self.next_free - 1;
+            self.block_mut(last / 64).block.set_runend(last % 64, false);
+        } else {
+            self.finish_run(quotient)?;
+        }
+        let bits = self.qf.slot_bits() as usize;
+        for (i, &slot) in slots.iter().enumerate() {
+            let index = start + i;
+            set_bits(&mut self.block_mut(index / 64).words, (index % 64) * bits, bits, slot);
+        }
+        let end = start + slots.len() - 1;
+        self.block_mut(end / 64).block.set_runend(end % 64, true);
+        self.block_mut(quotient / 64).block.set_occupied(quotient % 64, true);
+        self.qf.noccupied_slots += slots.len() as u64;
+        self.next_free = end + 1;
+        self.run_quotient = Some(quotient);
+        Ok(())
+    }
+
+    // sets the offsets the run just finished decides, now the next run is known to be at
+    // next_quotient, and writes out every block nothing after it can change
+    fn finish_run(&mut self, next_quotient: usize) -> Result<(), CqfError> {
+        let nblocks = self.qf.nblocks as usize;
+        if let Some(quotient) = self.run_quotient {
+            // the run is the last one for every block boundary up to the next quotient, and any
+            // of those it goes past start that far into their block
+            let end = self.next_free - 1;
+            let mut block_idx = quotient / 64 + 1;
+            while block_idx < nblocks && 64 * block_idx <= end && 64 * block_idx <= next_quotient {
+                self.block_mut(block_idx).block.offset = (end + 1 - 64 * block_idx) as u64;
+                block_idx += 1;
+            }
+        }
+        while self.first_block < nblocks && 64 * (self.first_block + 1) <= next_quotient {
+            self.block_mut(self.first_block);
+            let BlockSlots { block, words } = self.window.pop_front().unwrap_or_else(|| unreachable!());
+            let block = [block];
+            self.blocks.write_all(format::as_bytes(&block))?;
+            self.blocks_hasher.update(format::as_bytes(&block));
+            self.slots.write_all(format::as_bytes(&words))?;
+            self.slots_hasher.update(format::as_bytes(&words));
+            self.first_block += 1;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), CqfError> {
+        self.finish_run(usize::MAX)?;
+        self.blocks.flush()?;
+        self.slots.flush()?;
+        let header = format::header_bytes(&self.qf, self.blocks_hasher.digest(), self.slots_hasher.digest());
+        self.header.write_all(&header)?;
+        self.header.flush()?;
+        Ok(())
+    }
+}
+
+impl CQF {
+    // merges filter files into a new one without loading any of them, reading each a run at a
+    // time. Memory use doesn't depend on how many files there are or how big they are
+    pub fn merge_files(inputs: &[PathBuf], output: PathBuf) -> Result<(), CqfError> {
+        Self::merge_files_in_groups(inputs, &output, MERGE_FAN_IN)
+    }
+
+    // merges fan_in files at a time into temporary files next to the output, then merges those,
+    // until there are few enough left to merge straight into it
+    pub(crate) fn merge_files_in_groups(inputs: &[PathBuf], output: &Path, fan_in: usize) -> Result<(), CqfError> {
+        let mut level = 0;
+        let mut current = inputs.to_vec();
+        while current.len() > fan_in {
+            let mut merged = Vec::new();
+            let mut result = Ok(());
+            for (i, group) in current.chunks(fan_in).enumerate() {
+                let name = output.file_name().unwrap_or_default().to_string_lossy();
+                let path = output.with_file_name(format!("{}.merge-{}-{}", name, level, i));
+                merged.push(path.clone());
+                result = Self::merge_group(group, &path);
+                if result.is_err() {
+                    break;
+                }
+            }
+            // the inputs are the caller's, only the files from earlier levels are ours to remove
+            if level > 0 {
+                remove_all(&current);
+            }
+            if result.is_err() {
+                remove_all(&merged);
+            }
+            result?;
+            current = merged;
+            level += 1;
+        }
+        let result = Self::merge_group(&current, output);
+        if level > 0 {
+            remove_all(&current);
+        }
+        result
+    }
+
+    fn merge_group(inputs: &[PathBuf], output: &Path) -> Result<(), CqfError> {
+        let open = || inputs.iter().map(|path| CQFFileReader::open(path.clone())).collect::<Result<Vec<_>, _>>();
+        let mut readers = Some(open()?);
+        let (template, noccupied) = {
+            let layouts: Vec<&CQF> = readers.iter().flatten().map(|reader| reader.layout()).collect();
+            let first = Self::check_compatible(&layouts)?;
+            (first.layout_like(first.lognslots, first.quotient_bits)?, layouts.iter().map(|qf| qf.noccupied_slots).sum())
+        };
+        template.write_sorted(noccupied, output, || {
+            let current = match readers.take() {
+                Some(readers) => readers,
+                None => open()?,
+            };
+            // errors come out first so they aren't held up behind items, and the same hash comes
+            // out of earlier inputs first, so it's their values ValueMerge::Keep keeps
+            let items = current.into_iter().enumerate().map(|(i, reader)| reader.map(move |item| (i, item))).kmerge_by(|(i, a), (j, b)| match (a, b) {
+                (Ok(a), Ok(b)) => (a.hash, i) < (b.hash, j),
+                (Err(_), _) => true,
+                (Ok(_), Err(_)) => false,
+            });
+            Ok(items.map(|(_, item)| item))
+        })
+    }
+
+    // writes sorted items to a file laid out like this filter, sized the way build_sorted sizes
+    // an in-memory one, going through the items again at the next size up if they don't fit
+    pub(crate) fn write_sorted<I, F>(&self, noccupied: u64, output: &Path, mut items: F) -> Result<(), CqfError>
+    where
+        I: Iterator<Item = Result<FilterItem, CqfError>>,
+        F: FnMut() -> Result<I, CqfError>,
+    {
+        let (mut lognslots, max_lognslots) = self.sorted_lognslots(noccupied);
+        loop {
+            match SortedWriter::create(output, self.layout_like(lognslots, lognslots)?)?.write_all(items()?) {
+                Err(CqfError::CapacityExceeded) if lognslots < max_lognslots => lognslots += 1,
+                result => return result,
+            }
+        }
+    }
+}
+
+pub(crate) fn remove_all(paths: &[PathBuf]) {
+    for path in paths {
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,67 @@
+use std::{path::PathBuf, fs::File};
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use crate::{CQF, CQFIterator, CqfError, CqfKey, format};
+
+// a read-only filter answering queries straight out of a mapped file, so several processes can
+// share one copy of the pages
+pub struct CQFView {
+    qf: CQF,
+    map: Arc<Mmap>,
+}
+
+impl CQFView {
+    // the file mustn't be modified while it's mapped. Only the header is checked here, call
+    // verify to check the blocks and slots too
+    pub fn open(path: PathBuf) -> Result<Self, CqfError> {
+        let file = File::open(path)?;
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        Ok(Self { qf: format::map(map.clone())?, map })
+    }
+
+    pub fn verify(&self) -> Result<(), CqfError> {
+        format::verify(&self.qf, &self.map)
+    }
+
+    pub fn query(&self, item: u64) -> u64 {
+        self.qf.query(item)
+    }
+
+    pub fn query_key<K: CqfKey + ?Sized>(&self, key: &K) -> u64 {
+        self.qf.query_key(key)
+    }
+
+    pub fn query_by_hash(&self, hash: u64) -> u64 {
+        self.qf.query_by_hash(hash)
+    }
+
+    pub fn query_batch(&self, items: &[u64], counts: &mut [u64]) -> Result<(), CqfError> {
+        self.qf.query_batch(items, counts)
+    }
+
+    pub fn hash_key<K: CqfKey + ?Sized>(&self, key: &K) -> u64 {
+        self.qf.hash_key(key)
+    }
+
+    pub fn get_load_factor(&self) -> f32 {
+        self.qf.get_load_factor()
+    }
+
+    pub fn iter(&self) -> CQFIterator<'_> {
+        self.qf.into_iter()
+    }
+
+    pub fn iter_from(&self, hash: u64) -> CQFIterator<'_> {
+        self.qf.iter_from(hash)
+    }
+}
+
+impl<'a> IntoIterator for &'a CQFView {
+    type Item = crate::FilterItem;
+    type IntoIter = CQFIterator<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}